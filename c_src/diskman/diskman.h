@@ -1,6 +0,0 @@
-#ifndef DISKMAN_H
-#define DISKMAN_H
-
-unsigned long long int du(char path[]);
-
-#endif
@@ -0,0 +1,397 @@
+use crate::{crypto::to_hex, utils::human_readable_size};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::create_dir_all,
+    io::{self, PipeReader, PipeWriter, Read, Write, pipe},
+    path::{Component, Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+use tar::{Archive, Builder, Entry, EntryType};
+
+// Directories are sent as a plain (uncompressed) tar that is built while it is being sent, so the
+// archive never has to exist on disk on either side
+
+fn build_tar<W: Write>(dir: &Path, writer: W) -> io::Result<W> {
+    let mut tar = Builder::new(writer);
+    tar.append_dir_all("", dir)?;
+    tar.into_inner()
+}
+
+// Counts and hashes everything written to it without keeping any of it
+struct Measure {
+    len: u64,
+    hasher: Sha256,
+}
+
+impl Write for Measure {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.len += buf.len() as u64;
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Exact size and SHA-256 of the tar a directory streams as, found by building it once without
+// storing it. The offer needs both before anything is sent
+pub fn tar_summary(dir: &Path) -> io::Result<(u64, String)> {
+    let measure = build_tar(
+        dir,
+        Measure {
+            len: 0,
+            hasher: Sha256::new(),
+        },
+    )?;
+    Ok((measure.len, to_hex(&measure.hasher.finalize())))
+}
+
+// Builds the tar of a directory on a background thread, it is read from the returned pipe as fast
+// as the transfer can take it
+pub fn tar_reader(dir: &Path) -> io::Result<(PipeReader, JoinHandle<io::Result<()>>)> {
+    let (reader, writer) = pipe()?;
+    let dir = dir.to_path_buf();
+    let handle = thread::spawn(move || build_tar(&dir, writer).map(drop));
+    Ok((reader, handle))
+}
+
+// Something in a received archive that was left out while unpacking, and why
+pub struct Skipped {
+    pub path: String,
+    pub reason: String,
+}
+
+// Unpacks a tar made by the sender, so nothing in it is taken on trust: entries have to stay
+// inside dest, links may only point inside it, devices, fifos and the like are left out and no
+// more than limit bytes of file contents (the size the offer advertised) are written. Returns
+// what was left out
+pub fn unpack_checked<R: Read>(reader: R, dest: &Path, limit: u64) -> io::Result<Vec<Skipped>> {
+    let root = dest.canonicalize()?;
+    let mut archive = Archive::new(reader);
+    let mut skipped = Vec::new();
+    let mut written: u64 = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let kind = entry.header().entry_type();
+        let file = matches!(kind, EntryType::Regular | EntryType::Continuous);
+        // Past the advertised size the archive is lying about itself, stop rather than fill the disk
+        if file
+            && written
+                .checked_add(entry.size())
+                .is_none_or(|total| total > limit)
+        {
+            skipped.push(Skipped {
+                path: path.display().to_string(),
+                reason: format!(
+                    "the archive unpacks to more than the {} offered, nothing from here on was unpacked",
+                    human_readable_size(limit)
+                ),
+            });
+            break;
+        }
+        let refused = match refuse(&entry, &path, &root) {
+            Some(reason) => Some(reason),
+            None if !entry.unpack_in(&root)? => Some("it would be unpacked outside the folder"),
+            None => None,
+        };
+        match refused {
+            Some(reason) => skipped.push(Skipped {
+                path: path.display().to_string(),
+                reason: reason.to_string(),
+            }),
+            None if file => written += entry.size(),
+            None => {}
+        }
+    }
+    Ok(skipped)
+}
+
+// Why an entry can't be unpacked into root, if there is a reason. Pax headers only describe the
+// entries after them and are let through for the tar crate to deal with
+fn refuse<R: Read>(entry: &Entry<R>, path: &Path, root: &Path) -> Option<&'static str> {
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Some("its path leads out of the folder");
+    }
+    let kind = entry.header().entry_type();
+    match kind {
+        EntryType::Regular | EntryType::Continuous | EntryType::Directory => None,
+        _ if kind.is_pax_global_extensions() || kind.is_pax_local_extensions() => None,
+        EntryType::Symlink | EntryType::Link => {
+            let Ok(Some(target)) = entry.link_name() else {
+                return Some("it is a link without a target");
+            };
+            // Symlinks are relative to the folder they are in, hard links to the archive root
+            let from = match kind {
+                EntryType::Symlink => path.parent().unwrap_or(Path::new("")),
+                _ => Path::new(""),
+            };
+            (!link_stays_inside(root, from, &target)).then_some("it links to outside the folder")
+        }
+        _ => Some("special files such as devices and pipes are not unpacked"),
+    }
+}
+
+// Whether a link target followed from the folder `from` (relative to root) ends up inside root.
+// The folder is resolved on disk since earlier links may lead elsewhere, after that ".." is only
+// allowed at the start of the target so a link further along can't redirect where it climbs from
+fn link_stays_inside(root: &Path, from: &Path, target: &Path) -> bool {
+    let from = root.join(from);
+    if create_dir_all(&from).is_err() {
+        return false;
+    }
+    let Ok(mut at) = from.canonicalize() else {
+        return false;
+    };
+    let mut climbing = true;
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if climbing => {
+                at.pop();
+            }
+            Component::Normal(name) => {
+                climbing = false;
+                at.push(name);
+            }
+            _ => return false,
+        }
+    }
+    at.starts_with(root)
+}
+
+// Receiving side of a streamed directory. Everything written to it is hashed and unpacked into the
+// destination on a background thread as it arrives, with the checks of unpack_checked
+pub struct UnpackSink {
+    pipe: Option<PipeWriter>,
+    hasher: Sha256,
+    handle: JoinHandle<io::Result<Vec<Skipped>>>,
+}
+
+impl UnpackSink {
+    // limit is the size the offer advertised, see unpack_checked
+    pub fn new(dest: &Path, limit: u64) -> io::Result<UnpackSink> {
+        let (reader, writer) = pipe()?;
+        let dest: PathBuf = dest.to_path_buf();
+        let handle = thread::spawn(move || unpack_checked(reader, &dest, limit));
+        Ok(UnpackSink {
+            pipe: Some(writer),
+            hasher: Sha256::new(),
+            handle,
+        })
+    }
+
+    // Waits for unpacking to finish, returning the SHA-256 of everything received and whether
+    // unpacking worked along with what it left out
+    pub fn finish(mut self) -> (String, io::Result<Vec<Skipped>>) {
+        // Closing the pipe is what tells the unpacking thread the archive is over
+        self.pipe = None;
+        let result = self
+            .handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("Unpacking thread panicked")));
+        (to_hex(&self.hasher.finalize()), result)
+    }
+}
+
+impl Write for UnpackSink {
+    // If unpacking fails part way the rest of the stream is still taken (and hashed) so the
+    // transfer can finish, the error is reported by finish
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        if let Some(pipe) = &mut self.pipe
+            && pipe.write_all(buf).is_err()
+        {
+            self.pipe = None;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use tar::Header;
+
+    // An empty folder of its own for each test to unpack into
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("snd-archive-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        create_dir_all(dir.join("root")).unwrap();
+        dir
+    }
+
+    // Appends an entry with its name and link target written straight into the header, the tar
+    // crate's builder refuses to make most of the hostile ones
+    fn entry(tar: &mut Vec<u8>, kind: EntryType, path: &str, link: &str, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        let old = header.as_old_mut();
+        old.name[..path.len()].copy_from_slice(path.as_bytes());
+        old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        tar.extend_from_slice(header.as_bytes());
+        tar.extend_from_slice(data);
+        tar.resize(tar.len().next_multiple_of(512), 0);
+    }
+
+    fn unpack(tar: &mut Vec<u8>, dest: &Path, limit: u64) -> Vec<String> {
+        tar.extend_from_slice(&[0; 1024]);
+        unpack_checked(tar.as_slice(), dest, limit)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.path)
+            .collect()
+    }
+
+    #[test]
+    fn unpacks_files_folders_and_links_inside() {
+        let dir = scratch("inside");
+        let root = dir.join("root");
+        let mut tar = Vec::new();
+        entry(&mut tar, EntryType::Directory, "a/", "", b"");
+        entry(&mut tar, EntryType::Regular, "a/f", "", b"hello");
+        entry(&mut tar, EntryType::Symlink, "a/s", "f", b"");
+        entry(&mut tar, EntryType::Symlink, "a/up", "../a/f", b"");
+        entry(&mut tar, EntryType::Link, "h", "a/f", b"");
+        assert!(unpack(&mut tar, &root, 5).is_empty());
+        assert_eq!(fs::read(root.join("a/f")).unwrap(), b"hello");
+        assert_eq!(fs::read(root.join("a/s")).unwrap(), b"hello");
+        assert_eq!(fs::read(root.join("a/up")).unwrap(), b"hello");
+        assert_eq!(fs::read(root.join("h")).unwrap(), b"hello");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_paths_leading_out() {
+        let dir = scratch("paths");
+        let root = dir.join("root");
+        let absolute = dir.join("absolute");
+        let mut tar = Vec::new();
+        entry(&mut tar, EntryType::Regular, "../parent", "", b"x");
+        entry(&mut tar, EntryType::Regular, "a/../../nested", "", b"x");
+        entry(
+            &mut tar,
+            EntryType::Regular,
+            absolute.to_str().unwrap(),
+            "",
+            b"x",
+        );
+        assert_eq!(unpack(&mut tar, &root, 3).len(), 3);
+        assert!(!dir.join("parent").exists());
+        assert!(!dir.join("nested").exists());
+        assert!(!absolute.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_links_leading_out() {
+        let dir = scratch("links");
+        let root = dir.join("root");
+        let mut tar = Vec::new();
+        entry(&mut tar, EntryType::Symlink, "parent", "..", b"");
+        entry(&mut tar, EntryType::Symlink, "d/out", "../../outside", b"");
+        entry(&mut tar, EntryType::Symlink, "late", "a/../../outside", b"");
+        entry(&mut tar, EntryType::Symlink, "absolute", "/etc/passwd", b"");
+        entry(&mut tar, EntryType::Link, "hard", "../outside", b"");
+        // Escaping through a link that was let in is caught by resolving it on disk
+        entry(&mut tar, EntryType::Symlink, "d/self", ".", b"");
+        entry(
+            &mut tar,
+            EntryType::Symlink,
+            "d/self/up",
+            "../../outside",
+            b"",
+        );
+        let skipped = unpack(&mut tar, &root, 1);
+        assert_eq!(
+            skipped,
+            ["parent", "d/out", "late", "absolute", "hard", "d/self/up"]
+        );
+        assert!(!root.join("parent").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_devices_and_pipes() {
+        let dir = scratch("special");
+        let root = dir.join("root");
+        let mut tar = Vec::new();
+        entry(&mut tar, EntryType::Char, "null", "", b"");
+        entry(&mut tar, EntryType::Block, "disk", "", b"");
+        entry(&mut tar, EntryType::Fifo, "pipe", "", b"");
+        assert_eq!(unpack(&mut tar, &root, 0), ["null", "disk", "pipe"]);
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stops_past_the_offered_size() {
+        let dir = scratch("oversize");
+        let root = dir.join("root");
+        let mut tar = Vec::new();
+        entry(&mut tar, EntryType::Regular, "first", "", b"1234");
+        entry(&mut tar, EntryType::Regular, "second", "", b"5678");
+        entry(&mut tar, EntryType::Regular, "third", "", b"9");
+        assert_eq!(unpack(&mut tar, &root, 6), ["second"]);
+        assert!(root.join("first").exists());
+        assert!(!root.join("second").exists());
+        assert!(!root.join("third").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stops_at_absurd_sizes() {
+        let dir = scratch("overflow");
+        let root = dir.join("root");
+        let mut tar = Vec::new();
+        entry(&mut tar, EntryType::Regular, "first", "", b"1234");
+        let mut header = Header::new_gnu();
+        header.set_path("huge").unwrap();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(u64::MAX / 2);
+        header.set_cksum();
+        tar.extend_from_slice(header.as_bytes());
+        assert_eq!(unpack(&mut tar, &root, u64::MAX / 4), ["huge"]);
+        assert!(!root.join("huge").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn link_targets_are_followed_from_their_folder() {
+        let dir = scratch("targets");
+        let root = dir.join("root").canonicalize().unwrap();
+        assert!(link_stays_inside(&root, Path::new(""), Path::new("a/b")));
+        assert!(link_stays_inside(&root, Path::new("d"), Path::new("../a")));
+        assert!(link_stays_inside(
+            &root,
+            Path::new("d/e"),
+            Path::new("./../../a")
+        ));
+        assert!(!link_stays_inside(&root, Path::new(""), Path::new("..")));
+        assert!(!link_stays_inside(
+            &root,
+            Path::new("d"),
+            Path::new("../../a")
+        ));
+        assert!(!link_stays_inside(
+            &root,
+            Path::new(""),
+            Path::new("a/../../b")
+        ));
+        assert!(!link_stays_inside(&root, Path::new(""), Path::new("/etc")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
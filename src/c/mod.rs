@@ -1,2 +0,0 @@
-pub mod diskman;
-pub mod tui;
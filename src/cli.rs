@@ -1,31 +1,48 @@
 use crate::{
-    modes::sh_init,
-    types::ShModes,
-    utils::{get_config_path, read_config, write_config},
+    daemon::rec_daemon,
+    modes::{sh_init, snd_headless},
 };
 use colored::Colorize;
+use snd::{
+    SndError,
+    progress::{json, set_json, set_quiet},
+    types::{Config, ShModes},
+    utils::{
+        expand_path, get_config_path, join_pairs, read_config, split_list, split_pairs,
+        write_config,
+    },
+};
 
 pub fn colored_rec_h() -> String {
     format!(
-        "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+        "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
         "exit:".yellow().bold(),
         "Exits the program".cyan(),
         "help:".yellow().bold(),
         "Prints this message".cyan(),
         "vdms:".yellow().bold(),
-        "View all received direct messages".cyan(),
+        "View all received direct messages with their age and status (pending, accepted, completed or expired)".cyan(),
         "rec".yellow().bold(),
-        "Accepts a dm from the machine, takes in the index of the wanted message as a param".cyan(),
+        "Accepts a dm from the machine, takes in the index of the wanted message and an optional destination (a folder if it ends in /, otherwise the new file name)".cyan(),
+        "reject:".yellow().bold(),
+        "Declines a dm and tells the sender, takes in the index of the message and an optional reason".cyan(),
+        "rejectall:".yellow().bold(),
+        "Declines every received dm, takes in an optional reason".cyan(),
+        "trust:".yellow().bold(),
+        "Trusts the device that sent a dm, takes in the index of the message as a param".cyan(),
+        "untrust:".yellow().bold(),
+        "Stops trusting a device, takes in a message index, peer name or fingerprint".cyan(),
+        "trusted:".yellow().bold(),
+        "Lists all trusted devices".cyan(),
     )
 }
 
 pub fn colorize_help() -> String {
     format!(
-        "{}\n{}{}{}{}\n{}{}{}\n{}{}{}\n{}{}{}\n{}{}{}\n{}{}{}\n",
+        "{}\n{}{}{}\n{}{}{}\n{}{}{}\n{}{}{}\n{}{}{}\n{}{}{}\n",
         "snd:".yellow().bold(),
         "--[(h)elp|(V)ersion|(r)ec|(s)nd|(c)onfig]".green(),
         "\n\nCommands parsed in the order listed, first recognised flag will be run\n\n",
-        "The file size is approx and can be off by a bit (this issue is mostly with folders)",
         "help:".yellow().bold(),
         "Prints this help message".cyan(),
         "\n",
@@ -34,10 +51,10 @@ pub fn colorize_help() -> String {
         "\n",
         "rec:".yellow().bold(),
         "Puts the program into receving mode".cyan(),
-        "\n",
+        "\n  To accept offers unattended using the config rules: --rec --daemon\n".yellow(),
         "snd:".yellow().bold(),
         "Puts the program into sending mode".cyan(),
-        "\n",
+        "\n  To send without prompting: --snd <path|glob>... --to <hostname|ip>\n  Add --quiet (-q) to either to hide the progress bar, or --json to get each transfer's summary as JSON\n  Exit codes: 1 transfer failed, 64 bad arguments, 66 no such path, 68 host not found, 69 network error, 74 file error, 75 offer not accepted, 77 offer declined, 78 no downloads folder, 130 cancelled\n  Ctrl-C while sending withdraws the offer or cancels the transfer, the receiver is told either way\n".yellow(),
         "config:".yellow().bold(),
         "View or change settings".cyan(),
         "\n  To change: --config set <key> <value>".yellow(),
@@ -45,22 +62,117 @@ pub fn colorize_help() -> String {
     )
 }
 
+// Every setting with its current value and a description, in the order they are listed
+fn config_entries(config: &Config) -> Vec<(&'static str, String, &'static str)> {
+    vec![
+        (
+            "send_method",
+            config.send_method.clone(),
+            "Legacy is faster at the cost of reliablity, semi-reliable is slower but more reliable, windowed keeps many packets in flight and is both fast and reliable, tcp streams the file over a TCP connection, parallel splits the file into several windowed streams for fast links",
+        ),
+        (
+            "follow_symlinks",
+            config.follow_symlinks.to_string(),
+            "Send what symbolic links point to, otherwise they are refused",
+        ),
+        (
+            "encrypt",
+            config.encrypt.to_string(),
+            "Encrypt offers and transfers with a pairing code the receiver has to type in",
+        ),
+        (
+            "parallel_streams",
+            config.parallel_streams.to_string(),
+            "How many streams the parallel send method splits a file into (1 to 64)",
+        ),
+        (
+            "auto_accept_hosts",
+            config.auto_accept_hosts.join(", "),
+            "Comma separated hostnames the receive daemon accepts offers from (* for any), trusted peers are always accepted",
+        ),
+        (
+            "max_file_size",
+            config.max_file_size.to_string(),
+            "Largest offer in bytes the receive daemon accepts, 0 for no limit",
+        ),
+        (
+            "allowed_file_types",
+            config.allowed_file_types.join(", "),
+            "Comma separated file types the receive daemon accepts (e.g. directory, Text file), empty for any",
+        ),
+        (
+            "peer_quota",
+            config.peer_quota.to_string(),
+            "Bytes a single peer may send the receive daemon while it runs, 0 for no limit",
+        ),
+        (
+            "max_retries",
+            config.max_retries.to_string(),
+            "Times a packet is resent without an answer before the sender gives up",
+        ),
+        (
+            "idle_timeout",
+            config.idle_timeout.to_string(),
+            "Seconds either side waits without hearing from the other before giving up on a transfer",
+        ),
+        (
+            "download_dir",
+            config.download_dir.clone(),
+            "Folder received files are saved to, empty for the system downloads folder",
+        ),
+        (
+            "sender_dirs",
+            join_pairs(&config.sender_dirs),
+            "Comma separated hostname:folder or fingerprint:folder pairs, files from those senders are saved to their folder instead",
+        ),
+        (
+            "on_collision",
+            config.on_collision.clone(),
+            "What to do when a received file or folder already exists: rename adds a number to the new one, overwrite replaces the old one, skip leaves it alone, prompt asks (the receive daemon renames instead)",
+        ),
+    ]
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn invalid_bool(key: &str) -> String {
+    format!(
+        "{}\n{}",
+        format!("Invalid value for {}!", key).red(),
+        "Valid options: true/yes/1, false/no/0".yellow()
+    )
+}
+
 fn handle_config_subcommand(args: &[String]) -> String {
     if args.is_empty() {
         let config = read_config();
-        let path = get_config_path();
+        let stored = match get_config_path() {
+            Ok(path) => format!("(Stored at: {})", path.display()),
+            Err(e) => format!("({}, using the defaults)", e),
+        };
 
-        format!(
-            "{}\n{}\n\n  {}: {}\n    {}\n\n  {}: {}\n    {}\n\n{}\n{}\n{}",
+        let mut out = format!(
+            "{}\n{}\n",
             "Available settings:".yellow().bold(),
-            format!("(Stored at: {})", path.display()).dimmed(),
-            "1. send_method".green().bold(),
-            config.send_method,
-            "Legacy is faster at the cost of reliablity, semi-reliable is slower but more reliable"
-                .cyan(),
-            "2. follow_symlinks".green().bold(),
-            config.follow_symlinks,
-            "Follow symbolic links when calculating file sizes".cyan(),
+            stored.dimmed(),
+        );
+        for (i, (key, value, description)) in config_entries(&config).iter().enumerate() {
+            out.push_str(&format!(
+                "\n  {}: {}\n    {}\n",
+                format!("{}. {}", i + 1, key).green().bold(),
+                value,
+                description.cyan()
+            ));
+        }
+        format!(
+            "{}\n{}\n{}\n{}",
+            out,
             "Send methods will always be decided based on who is sending the file".yellow(),
             "To change: --config set <key> <value>".yellow(),
             "To reset: --config reset".yellow(),
@@ -77,6 +189,8 @@ fn handle_config_subcommand(args: &[String]) -> String {
 
         let key = &args[1].to_lowercase();
         let value = &args[2].to_lowercase();
+        // Folders keep the case they were typed in
+        let raw = &args[2];
         let mut config = read_config();
 
         match key.as_str() {
@@ -84,24 +198,81 @@ fn handle_config_subcommand(args: &[String]) -> String {
                 config.send_method = match value.as_str() {
                     "legacy" | "1" => "legacy".to_string(),
                     "semi-reliable" | "2" => "semi-reliable".to_string(),
+                    "windowed" | "3" => "windowed".to_string(),
+                    "tcp" | "4" => "tcp".to_string(),
+                    "parallel" | "5" => "parallel".to_string(),
                     _ => {
                         return format!(
                             "{}\n{}",
                             "Invalid value for send_method!".red(),
-                            "Valid options: legacy (or 1), semi-reliable (or 2)".yellow()
+                            "Valid options: legacy (or 1), semi-reliable (or 2), windowed (or 3), tcp (or 4), parallel (or 5)"
+                                .yellow()
                         );
                     }
                 };
             }
-            "follow_symlinks" => {
-                config.follow_symlinks = match value.as_str() {
-                    "true" | "1" | "yes" | "on" => true,
-                    "false" | "0" | "no" | "off" => false,
+            "follow_symlinks" => match parse_bool(value) {
+                Some(b) => config.follow_symlinks = b,
+                None => return invalid_bool(key),
+            },
+            "encrypt" => match parse_bool(value) {
+                Some(b) => config.encrypt = b,
+                None => return invalid_bool(key),
+            },
+            "parallel_streams" => match value.parse::<u64>() {
+                Ok(n) if (1..=64).contains(&n) => config.parallel_streams = n,
+                _ => {
+                    return format!(
+                        "{}\n{}",
+                        "Invalid value for parallel_streams!".red(),
+                        "Valid options: a number from 1 to 64".yellow()
+                    );
+                }
+            },
+            "auto_accept_hosts" => config.auto_accept_hosts = split_list(value),
+            "allowed_file_types" => config.allowed_file_types = split_list(value),
+            "max_file_size" | "peer_quota" => match value.parse::<u64>() {
+                Ok(n) if key == "max_file_size" => config.max_file_size = n,
+                Ok(n) => config.peer_quota = n,
+                Err(_) => {
+                    return format!(
+                        "{}\n{}",
+                        format!("Invalid value for {}!", key).red(),
+                        "Valid options: a size in bytes, 0 for no limit".yellow()
+                    );
+                }
+            },
+            "max_retries" | "idle_timeout" => match value.parse::<u64>() {
+                Ok(n) if n >= 1 && key == "max_retries" => config.max_retries = n,
+                Ok(n) if n >= 1 => config.idle_timeout = n,
+                _ => {
+                    return format!(
+                        "{}\n{}",
+                        format!("Invalid value for {}!", key).red(),
+                        "Valid options: a number from 1 up".yellow()
+                    );
+                }
+            },
+            "download_dir" if raw.trim().is_empty() => config.download_dir = String::new(),
+            "download_dir" => config.download_dir = expand_path(raw).display().to_string(),
+            "sender_dirs" => {
+                config.sender_dirs = split_pairs(raw)
+                    .into_iter()
+                    .map(|(sender, dir)| (sender, expand_path(&dir).display().to_string()))
+                    .collect()
+            }
+            "on_collision" => {
+                config.on_collision = match value.as_str() {
+                    "rename" | "1" => "rename".to_string(),
+                    "overwrite" | "2" => "overwrite".to_string(),
+                    "skip" | "3" => "skip".to_string(),
+                    "prompt" | "4" => "prompt".to_string(),
                     _ => {
                         return format!(
                             "{}\n{}",
-                            "Invalid value for follow_symlinks!".red(),
-                            "Valid options: true/yes/1, false/no/0".yellow()
+                            "Invalid value for on_collision!".red(),
+                            "Valid options: rename (or 1), overwrite (or 2), skip (or 3), prompt (or 4)"
+                                .yellow()
                         );
                     }
                 };
@@ -110,38 +281,35 @@ fn handle_config_subcommand(args: &[String]) -> String {
                 return format!(
                     "{}\n{}",
                     "Invalid config key!".red(),
-                    "Valid keys: send_method, follow_symlinks".yellow()
+                    "Valid keys: send_method, follow_symlinks, encrypt, parallel_streams, auto_accept_hosts, max_file_size, allowed_file_types, peer_quota, max_retries, idle_timeout, download_dir, sender_dirs, on_collision".yellow()
                 );
             }
         }
 
-        if let Err(e) = write_config(&config) {
-            return format!("{}: {}", "Failed to save config".red(), e);
-        }
+        let path = match write_config(&config).and_then(|()| get_config_path()) {
+            Ok(path) => path,
+            Err(e) => return format!("{}: {}", "Failed to save config".red(), e),
+        };
 
         format!(
             "{} {} = {}\n{}",
             "Config setting".green(),
             key.bright_cyan().bold(),
-            value.bright_cyan().bold(),
-            format!("(Updated at: {})", get_config_path().display()).dimmed()
+            raw.bright_cyan().bold(),
+            format!("(Updated at: {})", path.display()).dimmed()
         )
     } else if args[0] == "reset" {
-        let default_config = crate::types::Config {
-            send_method: "semi-reliable".to_string(),
-            follow_symlinks: false,
-        };
+        let default_config = Config::default();
 
         if let Err(e) = write_config(&default_config) {
             return format!("{}: {}", "Failed to reset config".red(), e);
         }
 
-        format!(
-            "{}\n{}\n{}",
-            "Config reset to default values:".green(),
-            format!("  send_method = {}", default_config.send_method),
-            format!("  follow_symlinks = {}", default_config.follow_symlinks)
-        )
+        let mut out = "Config reset to default values:".green().to_string();
+        for (key, value, _) in config_entries(&default_config) {
+            out.push_str(&format!("\n  {} = {}", key, value));
+        }
+        out
     } else {
         format!(
             "{}\n{}",
@@ -152,31 +320,64 @@ fn handle_config_subcommand(args: &[String]) -> String {
     }
 }
 
-pub fn parse(args: &[String]) -> String {
+// Printed once a transfer is over, left out with --json so stdout is nothing but JSON lines
+fn done() -> String {
+    if json() {
+        String::new()
+    } else {
+        "Done.".bright_green().to_string()
+    }
+}
+
+pub fn parse(args: &[String]) -> Result<String, SndError> {
+    set_quiet(args.iter().any(|a| a == "--quiet" || a == "-q"));
+    set_json(args.iter().any(|a| a == "--json"));
     for (index, arg) in args.iter().enumerate() {
         match arg.as_str() {
-            "--help" | "-h" => return colorize_help(),
+            "--help" | "-h" => return Ok(colorize_help()),
             "--version" | "-V" => {
-                return env!("CARGO_PKG_VERSION").bright_cyan().bold().to_string()
+                return Ok(env!("CARGO_PKG_VERSION").bright_cyan().bold().to_string());
             }
             "--rec" | "-r" => {
-                sh_init(ShModes::REC);
-                return "Done.".bright_green().to_string();
+                if args.iter().any(|a| a == "--daemon" || a == "-d") {
+                    rec_daemon()?;
+                    return Ok(done());
+                }
+                sh_init(ShModes::REC)?;
+                return Ok(done());
             }
             "--snd" | "-s" => {
-                sh_init(ShModes::SND);
-                return "Done.".bright_green().to_string();
+                let paths: Vec<&str> = args[index + 1..]
+                    .iter()
+                    .take_while(|a| !a.starts_with('-'))
+                    .map(|a| a.as_str())
+                    .collect();
+                let to = args
+                    .iter()
+                    .position(|a| a == "--to" || a == "-t")
+                    .and_then(|i| args.get(i + 1));
+                match (paths.is_empty(), to) {
+                    (true, None) => sh_init(ShModes::SND)?,
+                    (false, Some(to)) => snd_headless(&paths, to)?,
+                    _ => {
+                        return Err(SndError::Usage(
+                            "Both paths and a target are needed to send without prompting\nUsage: --snd <path|glob>... --to <hostname|ip>"
+                                .to_string(),
+                        ));
+                    }
+                }
+                return Ok(done());
             }
             "--config" | "-c" => {
                 let rest_args = args.get(index + 1..).unwrap_or_default();
-                return handle_config_subcommand(rest_args);
+                return Ok(handle_config_subcommand(rest_args));
             }
             _ => {}
         }
     }
-    format!(
+    Ok(format!(
         "{}\n{}",
         "Command option not found".red().bold(),
         colorize_help()
-    )
+    ))
 }
@@ -0,0 +1,172 @@
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit},
+};
+use curve25519_dalek::{
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+
+// Bytes a sealed message is longer than its plaintext, the nonce plus the Poly1305 tag
+pub const SEAL_OVERHEAD: usize = 24 + 16;
+
+const WORDS: [&str; 64] = [
+    "amber", "anchor", "apple", "arrow", "badger", "bamboo", "banjo", "beacon", "birch", "bison",
+    "cactus", "canyon", "cedar", "cobalt", "comet", "coral", "cricket", "crimson", "delta",
+    "dingo", "ember", "falcon", "fern", "fjord", "garnet", "gecko", "glacier", "harbor", "hazel",
+    "heron", "indigo", "iris", "jasper", "juniper", "kayak", "kettle", "lagoon", "lantern",
+    "lemur", "lilac", "maple", "meadow", "nectar", "nickel", "nutmeg", "oasis", "orbit", "otter",
+    "pepper", "pebble", "quartz", "quill", "raven", "saffron", "sierra", "tango", "thistle",
+    "tundra", "umber", "velvet", "walnut", "willow", "yonder", "zephyr",
+];
+
+// A short code like 4821-crimson-otter that the sender shows and the receiver types in
+pub fn gen_pairing_code() -> String {
+    let n = OsRng.next_u32();
+    format!(
+        "{:04}-{}-{}",
+        n % 10000,
+        WORDS[(n >> 14) as usize % 64],
+        WORDS[(n >> 20) as usize % 64]
+    )
+}
+
+#[derive(Clone, Copy)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+// One side of a SPAKE2 exchange over ristretto255. Both sides blind an ephemeral key with the
+// pairing code, so someone watching (or even tampering with) the exchange only gets one online
+// guess at the code instead of being able to brute force it afterwards
+pub struct Pake {
+    role: Role,
+    secret: Scalar,
+    password: Scalar,
+    message: [u8; 32],
+}
+
+impl Pake {
+    pub fn start(role: Role, code: &str) -> Pake {
+        let password = Scalar::hash_from_bytes::<Sha512>(normalise_code(code).as_bytes());
+        let secret = Scalar::random(&mut OsRng);
+        let blind = match role {
+            Role::Sender => blinding_point(b"snd spake2 M"),
+            Role::Receiver => blinding_point(b"snd spake2 N"),
+        };
+        let message = (RistrettoPoint::mul_base(&secret) + blind * password)
+            .compress()
+            .to_bytes();
+        Pake {
+            role,
+            secret,
+            password,
+            message,
+        }
+    }
+
+    // The message to send to the other side
+    pub fn message(&self) -> [u8; 32] {
+        self.message
+    }
+
+    // Derives the session from the other side's message. A wrong pairing code still produces a
+    // session, it just can't open anything the other side seals
+    pub fn finish(self, peer: &[u8]) -> Option<Session> {
+        let peer_point = CompressedRistretto::from_slice(peer).ok()?.decompress()?;
+        let unblind = match self.role {
+            Role::Sender => blinding_point(b"snd spake2 N"),
+            Role::Receiver => blinding_point(b"snd spake2 M"),
+        };
+        let shared = (peer_point - unblind * self.password) * self.secret;
+
+        let (sender_msg, receiver_msg) = match self.role {
+            Role::Sender => (&self.message[..], peer),
+            Role::Receiver => (peer, &self.message[..]),
+        };
+        let mut transcript = Sha256::new();
+        transcript.update(b"snd spake2 transcript");
+        transcript.update(sender_msg);
+        transcript.update(receiver_msg);
+        transcript.update(shared.compress().as_bytes());
+        transcript.update(self.password.as_bytes());
+        let transcript = transcript.finalize();
+
+        let sender_key = direction_key(&transcript, b"sender to receiver");
+        let receiver_key = direction_key(&transcript, b"receiver to sender");
+        let (seal_key, open_key) = match self.role {
+            Role::Sender => (sender_key, receiver_key),
+            Role::Receiver => (receiver_key, sender_key),
+        };
+        Some(Session {
+            seal: XChaCha20Poly1305::new(&seal_key.into()),
+            open: XChaCha20Poly1305::new(&open_key.into()),
+        })
+    }
+}
+
+// Authenticated encryption for everything sent after pairing, each direction has its own key so
+// a message can't be reflected back at the side that sent it
+pub struct Session {
+    seal: XChaCha20Poly1305,
+    open: XChaCha20Poly1305,
+}
+
+impl Session {
+    // Encrypts a message, the random nonce is sent in front of the ciphertext
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .seal
+            .encrypt(&nonce, plaintext)
+            .expect("Failed to encrypt message");
+        let mut sealed = Vec::with_capacity(SEAL_OVERHEAD + plaintext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    // Decrypts a sealed message, None if it was tampered with or sealed with another key
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(24);
+        self.open
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Codes are compared case and whitespace insensitively so typing them is forgiving
+fn normalise_code(code: &str) -> String {
+    code.trim().to_lowercase().replace(' ', "-")
+}
+
+fn blinding_point(label: &[u8]) -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(label)
+}
+
+fn direction_key(transcript: &[u8], label: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(transcript);
+    hasher.update(label);
+    hasher.finalize().into()
+}
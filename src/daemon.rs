@@ -0,0 +1,259 @@
+use snd::{
+    DM, OfferedFile, Receiver, SndError,
+    identity::is_trusted,
+    types::{Collision, Config},
+    utils::{gen_cname, human_readable_size, read_config},
+};
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Unattended receiver: every offer is checked against the policy in the config and either
+// accepted straight away or rejected, never asking anything on stdin
+pub fn rec_daemon() -> Result<(), SndError> {
+    let receiver = Receiver::bind()?;
+    log(&format!("Receive daemon started as {}", gen_cname()));
+
+    // Bytes received from each peer (its identity, or hostname if it didn't sign) for peer_quota
+    let mut received: HashMap<String, u64> = HashMap::new();
+    loop {
+        let dm = match receiver.next_offer() {
+            Ok(dm) => dm,
+            Err(e) => {
+                log(&e.to_string());
+                continue;
+            }
+        };
+
+        // Re-read every time so policy changes apply without restarting the daemon
+        let config = read_config();
+        let peer = dm
+            .identity
+            .clone()
+            .unwrap_or_else(|| dm.host_info.name.clone());
+        let from = format!("{} ({})", dm.host_info.name, dm.host_info.ip);
+        if let Err(reason) = check_offer(&dm, &config) {
+            log(&format!("Rejected offer from {}: {}", from, reason));
+            decline(&receiver, &dm, &reason);
+            continue;
+        }
+
+        // Each file of the offer is judged on its own, the ones that pass are accepted
+        let mut used = received.get(&peer).copied().unwrap_or(0);
+        let mut chosen = Vec::new();
+        for (i, file) in dm.files.iter().enumerate() {
+            match check_file(file, &config, used) {
+                Ok(()) => {
+                    used = used.saturating_add(file.size);
+                    chosen.push(i);
+                    log(&format!(
+                        "Accepted {} ({}) from {}",
+                        file.path,
+                        human_readable_size(file.size),
+                        from
+                    ));
+                }
+                Err(reason) => log(&format!("Rejected {} from {}: {}", file.path, from, reason)),
+            }
+        }
+        if chosen.is_empty() {
+            decline(&receiver, &dm, "none of the files are allowed");
+            continue;
+        }
+
+        let accepted_size = chosen
+            .iter()
+            .fold(0, |total: u64, &i| total.saturating_add(dm.files[i].size));
+        // Nobody is around to answer a prompt, so that falls back to renaming
+        let collision = Collision::from_config(&config.on_collision).unwrap_or(Collision::Rename);
+        let on_collision = |path: &Path| {
+            let action = match collision {
+                Collision::Rename => "saving under a new name",
+                Collision::Overwrite => "overwriting it",
+                Collision::Skip => "skipping it",
+            };
+            log(&format!("{} already exists, {}", path.display(), action));
+            collision
+        };
+        match receiver.accept(&dm, None, None, |_| chosen, on_collision, |_, _| {}) {
+            Ok(()) => {
+                let total = received.entry(peer).or_default();
+                *total = total.saturating_add(accepted_size);
+                log(&format!("Received everything accepted from {}", from));
+            }
+            Err(e) => log(&format!("Transfer from {} did not complete: {}", from, e)),
+        }
+    }
+}
+
+// Lets the sender know straight away instead of leaving it waiting for an answer
+fn decline(receiver: &Receiver, dm: &DM, reason: &str) {
+    if let Err(e) = receiver.reject(dm, Some(reason)) {
+        log(&e.to_string());
+    }
+}
+
+// Why an offer can't be accepted at all, if there is a reason
+fn check_offer(dm: &DM, config: &Config) -> Result<(), String> {
+    if dm.sealed {
+        return Err("encrypted offers need the pairing code typed in".to_string());
+    }
+    // No real set of files adds up to more than a u64, such an offer is made up
+    if dm
+        .files
+        .iter()
+        .try_fold(0, |total: u64, f| total.checked_add(f.size))
+        .is_none()
+    {
+        return Err("the file sizes add up to more than is possible".to_string());
+    }
+    let trusted = dm.identity.as_deref().is_some_and(is_trusted);
+    let host_allowed = config
+        .auto_accept_hosts
+        .iter()
+        .any(|h| h == "*" || h.eq_ignore_ascii_case(&dm.host_info.name));
+    if !trusted && !host_allowed {
+        return Err("host is not trusted or in auto_accept_hosts".to_string());
+    }
+    Ok(())
+}
+
+// Why a single file of an offer shouldn't be accepted, given how much the peer already sent
+fn check_file(file: &OfferedFile, config: &Config, used: u64) -> Result<(), String> {
+    if config.max_file_size > 0 && file.size > config.max_file_size {
+        return Err(format!(
+            "size {} is over max_file_size {}",
+            human_readable_size(file.size),
+            human_readable_size(config.max_file_size)
+        ));
+    }
+    if !config.allowed_file_types.is_empty()
+        && !config
+            .allowed_file_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(&file.file_type))
+    {
+        return Err(format!("file type {} is not allowed", file.file_type));
+    }
+    if config.peer_quota > 0
+        && used
+            .checked_add(file.size)
+            .is_none_or(|total| total > config.peer_quota)
+    {
+        return Err(format!(
+            "peer would go over its quota, {} of {} used",
+            human_readable_size(used),
+            human_readable_size(config.peer_quota)
+        ));
+    }
+    Ok(())
+}
+
+// Every decision is logged with a unix timestamp so the output can be kept as a log file
+fn log(message: &str) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    status!("[{}] {}", now, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use snd::HostInfo;
+    use std::{net::IpAddr, time::Duration};
+
+    fn file(size: u64, file_type: &str) -> OfferedFile {
+        OfferedFile {
+            path: "a.bin".to_string(),
+            file_type: file_type.to_string(),
+            size,
+            digest: None,
+            streamed: false,
+        }
+    }
+
+    // An unsigned offer from alpha
+    fn offer(files: Vec<OfferedFile>) -> DM {
+        let now = SystemTime::now();
+        DM {
+            id: 1,
+            received: now,
+            expires: now + Duration::from_secs(30),
+            host_info: HostInfo {
+                name: "alpha".to_string(),
+                ip: IpAddr::from([10, 0, 0, 1]),
+                version: 1,
+            },
+            send_method: "windowed".to_string(),
+            streams: 1,
+            files,
+            sealed: false,
+            identity: None,
+        }
+    }
+
+    fn accepting(hosts: &[&str]) -> Config {
+        Config {
+            auto_accept_hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn offers_are_only_taken_from_listed_hosts() {
+        let dm = offer(vec![file(1, "Binary file")]);
+        assert!(check_offer(&dm, &Config::default()).is_err());
+        assert!(check_offer(&dm, &accepting(&["bravo"])).is_err());
+        assert!(check_offer(&dm, &accepting(&["ALPHA"])).is_ok());
+        assert!(check_offer(&dm, &accepting(&["*"])).is_ok());
+    }
+
+    #[test]
+    fn sealed_and_impossible_offers_are_refused() {
+        let config = accepting(&["*"]);
+        let mut dm = offer(vec![file(1, "Binary file")]);
+        dm.sealed = true;
+        assert!(check_offer(&dm, &config).is_err());
+
+        let dm = offer(vec![file(u64::MAX, "Binary file"), file(1, "Binary file")]);
+        assert!(check_offer(&dm, &config).is_err());
+    }
+
+    #[test]
+    fn files_over_max_file_size_are_refused() {
+        let config = Config {
+            max_file_size: 100,
+            ..Config::default()
+        };
+        assert!(check_file(&file(100, "Binary file"), &config, 0).is_ok());
+        assert!(check_file(&file(101, "Binary file"), &config, 0).is_err());
+        // No limit when it is 0
+        assert!(check_file(&file(u64::MAX, "Binary file"), &Config::default(), 0).is_ok());
+    }
+
+    #[test]
+    fn only_allowed_file_types_are_taken() {
+        let config = Config {
+            allowed_file_types: vec!["text file".to_string()],
+            ..Config::default()
+        };
+        assert!(check_file(&file(1, "Text file"), &config, 0).is_ok());
+        assert!(check_file(&file(1, "Binary file"), &config, 0).is_err());
+    }
+
+    #[test]
+    fn peers_stay_within_their_quota() {
+        let config = Config {
+            peer_quota: 100,
+            ..Config::default()
+        };
+        assert!(check_file(&file(40, "Binary file"), &config, 60).is_ok());
+        assert!(check_file(&file(41, "Binary file"), &config, 60).is_err());
+        // Sizes that overflow the running total are over any quota
+        assert!(check_file(&file(u64::MAX, "Binary file"), &config, 60).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{error::Error, fmt, io};
+
+// Everything that can stop a transfer, each kind exits with its own code so scripts can tell
+// them apart
+#[derive(Debug)]
+pub enum SndError {
+    // A socket couldn't be opened or used, most often the port is taken by another snd
+    Network(String, io::Error),
+    // Reading or writing a file failed, e.g. the disk is full
+    Io(String, io::Error),
+    // There is no downloads folder to save into
+    NoDownloadDir,
+    // Nothing to send at the given paths
+    InvalidPath(String),
+    // The host to send to never showed up
+    HostNotFound(String),
+    // The receiver never accepted the offer
+    NotAccepted,
+    // The receiver turned the offer down, with its reason if it gave one
+    Declined { by: String, reason: String },
+    // A transfer started but didn't complete or arrived corrupt
+    TransferFailed(String),
+    // The offer or transfer was cancelled on this side
+    Cancelled,
+    // The command line didn't make sense, with what was wrong and how it is used
+    Usage(String),
+}
+
+impl SndError {
+    // For map_err, e.g. .map_err(SndError::io("Failed to open file"))
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> SndError {
+        let context = context.into();
+        move |e| SndError::Io(context, e)
+    }
+
+    pub fn network(context: impl Into<String>) -> impl FnOnce(io::Error) -> SndError {
+        let context = context.into();
+        move |e| SndError::Network(context, e)
+    }
+
+    // Codes from sysexits.h where one fits, plain 1 for a transfer that didn't make it
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SndError::TransferFailed(_) => 1,
+            SndError::Usage(_) => 64,
+            SndError::InvalidPath(_) => 66,
+            SndError::HostNotFound(_) => 68,
+            SndError::Network(..) => 69,
+            SndError::Io(..) => 74,
+            SndError::NotAccepted => 75,
+            SndError::Declined { .. } => 77,
+            SndError::NoDownloadDir => 78,
+            // As if killed by Ctrl-C, which is how it usually happens
+            SndError::Cancelled => 130,
+        }
+    }
+}
+
+impl fmt::Display for SndError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SndError::Network(context, e) if e.kind() == io::ErrorKind::AddrInUse => {
+                write!(f, "{}: {} (is another snd running?)", context, e)
+            }
+            SndError::Network(context, e) | SndError::Io(context, e) => {
+                write!(f, "{}: {}", context, e)
+            }
+            SndError::NoDownloadDir => write!(f, "Could not find a downloads folder to save into"),
+            SndError::InvalidPath(reason) => write!(f, "{}", reason),
+            SndError::HostNotFound(host) => write!(f, "Host {} was not seen on the network", host),
+            SndError::NotAccepted => write!(f, "The receiver didn't accept the offer"),
+            SndError::Declined { by, reason } if reason.is_empty() => {
+                write!(f, "{} declined the offer", by)
+            }
+            SndError::Declined { by, reason } => write!(f, "{} declined the offer: {}", by, reason),
+            SndError::TransferFailed(reason) => write!(f, "{}", reason),
+            SndError::Cancelled => write!(f, "Cancelled"),
+            SndError::Usage(usage) => write!(f, "{}", usage),
+        }
+    }
+}
+
+impl Error for SndError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SndError::Network(_, e) | SndError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
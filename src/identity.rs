@@ -0,0 +1,131 @@
+use crate::{
+    crypto::{from_hex, to_hex},
+    error::SndError,
+    protocol::{Offer, Signature as OfferSignature},
+    utils::get_config_path,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{OpenOptions, create_dir_all, read_to_string, write},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+// The device's signing key, kept next to config.conf
+pub fn identity_path() -> io::Result<PathBuf> {
+    Ok(get_config_path()?.with_file_name("identity.key"))
+}
+
+pub fn trusted_peers_path() -> io::Result<PathBuf> {
+    Ok(get_config_path()?.with_file_name("trusted_peers.conf"))
+}
+
+// Loads this device's keypair, generating and saving a new one on first run
+pub fn load_identity() -> Result<SigningKey, SndError> {
+    let path = identity_path().map_err(SndError::io("Failed to find device identity"))?;
+    if let Ok(contents) = read_to_string(&path)
+        && let Some(bytes) = from_hex(contents.trim())
+        && let Ok(secret) = <[u8; 32]>::try_from(bytes.as_slice())
+    {
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+
+    let key = SigningKey::generate(&mut OsRng);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).map_err(SndError::io("Failed to create config directory"))?;
+    }
+    // Created private so the key is never readable by others, not even for a moment. A file
+    // that was already there keeps its mode on open, so that is tightened before writing too
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(&path)
+        .map_err(SndError::io("Failed to save device identity"))?;
+    #[cfg(unix)]
+    {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+        file.set_permissions(Permissions::from_mode(0o600))
+            .map_err(SndError::io(
+                "Failed to restrict device identity permissions",
+            ))?;
+    }
+    file.write_all(to_hex(&key.to_bytes()).as_bytes())
+        .map_err(SndError::io("Failed to save device identity"))?;
+    Ok(key)
+}
+
+// Our public key and a signature over the whole offer so the receiver can tell who sent it
+pub fn sign_offer(key: &SigningKey, offer: &Offer) -> OfferSignature {
+    OfferSignature {
+        key: key.verifying_key().to_bytes(),
+        signature: key.sign(&offer.signed_bytes()).to_bytes(),
+    }
+}
+
+// The sender's public key (hex), only if the offer was signed and the signature checks out
+pub fn verify_offer(offer: &Offer, signature: Option<&OfferSignature>) -> Option<String> {
+    let signature = signature?;
+    VerifyingKey::from_bytes(&signature.key)
+        .ok()?
+        .verify(
+            &offer.signed_bytes(),
+            &Signature::from_bytes(&signature.signature),
+        )
+        .ok()?;
+    Some(to_hex(&signature.key))
+}
+
+// Short, readable form of a public key for showing to people
+pub fn fingerprint(key: &str) -> String {
+    let hash = to_hex(&Sha256::digest(key.as_bytes()));
+    hash.as_bytes()[..16]
+        .chunks(4)
+        .map(|c| String::from_utf8_lossy(c).to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+pub struct TrustedPeer {
+    pub name: String,
+    pub key: String,
+}
+
+// Peers the receiver has trusted, stored as "name = key" lines
+pub fn read_trusted_peers() -> Vec<TrustedPeer> {
+    let Ok(contents) = trusted_peers_path().and_then(read_to_string) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (name, key) = line.rsplit_once('=')?;
+            Some(TrustedPeer {
+                name: name.trim().to_string(),
+                key: key.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn write_trusted_peers(peers: &[TrustedPeer]) -> io::Result<()> {
+    let path = trusted_peers_path()?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let contents: String = peers
+        .iter()
+        .map(|p| format!("{} = {}\n", p.name, p.key))
+        .collect();
+    write(path, contents)
+}
+
+pub fn is_trusted(key: &str) -> bool {
+    read_trusted_peers().iter().any(|p| p.key == key)
+}
@@ -0,0 +1,260 @@
+use colored::{ColoredString, Colorize};
+use snd::DM;
+use std::{
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// How long finished offers stay listed before they are dropped
+const KEEP_FINISHED: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Status {
+    // Waiting for an answer from us
+    Pending,
+    // Accepted, the transfer is running or didn't complete
+    Accepted,
+    Completed,
+    // The sender stopped waiting before we answered
+    Expired,
+}
+
+impl Status {
+    pub fn colored(self) -> ColoredString {
+        match self {
+            Status::Pending => "pending".yellow(),
+            Status::Accepted => "accepted".cyan(),
+            Status::Completed => "completed".green(),
+            Status::Expired => "expired".red(),
+        }
+    }
+}
+
+pub struct Entry {
+    pub dm: DM,
+    pub status: Status,
+    // When the status last changed, finished entries are dropped some time after
+    changed: Instant,
+}
+
+// The offers received by the interactive receiver, in the order they arrived
+#[derive(Default)]
+pub struct Inbox {
+    entries: Vec<Entry>,
+}
+
+impl Inbox {
+    // Adds a new offer. A resent offer still waiting for an answer replaces the older copy in
+    // place, so it keeps its index but gets the later expiry
+    pub fn add(&mut self, dm: DM) {
+        let resent = self
+            .entries
+            .iter_mut()
+            .find(|e| e.status == Status::Pending && dm.is_resend_of(&e.dm));
+        match resent {
+            Some(entry) => {
+                entry.dm = dm;
+                entry.changed = Instant::now();
+            }
+            None => self.entries.push(Entry {
+                dm,
+                status: Status::Pending,
+                changed: Instant::now(),
+            }),
+        }
+    }
+
+    // Drops an offer its sender took back, unless it was already answered
+    pub fn withdraw(&mut self, ip: IpAddr, id: u32) {
+        self.entries
+            .retain(|e| e.status != Status::Pending || e.dm.host_info.ip != ip || e.dm.id != id);
+    }
+
+    // Marks offers whose sender gave up as expired and forgets ones that finished a while ago
+    pub fn refresh(&mut self) {
+        for entry in &mut self.entries {
+            if entry.status == Status::Pending && entry.dm.is_expired() {
+                entry.status = Status::Expired;
+                entry.changed = Instant::now();
+            }
+        }
+        self.entries
+            .retain(|e| e.status == Status::Pending || e.changed.elapsed() < KEEP_FINISHED);
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // The entry of an offer, by the sender it came from and its id. Indexes shift whenever
+    // entries are dropped, these don't
+    pub fn find(&self, ip: IpAddr, id: u32) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.dm.host_info.ip == ip && e.dm.id == id)
+    }
+
+    pub fn set_status(&mut self, ip: IpAddr, id: u32, status: Status) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.dm.host_info.ip == ip && e.dm.id == id)
+        {
+            entry.status = status;
+            entry.changed = Instant::now();
+        }
+    }
+
+    pub fn remove(&mut self, idx: usize) -> DM {
+        self.entries.remove(idx).dm
+    }
+
+    // Takes out every offer still waiting for an answer
+    pub fn take_pending(&mut self) -> Vec<DM> {
+        let (pending, rest) = self
+            .entries
+            .drain(..)
+            .partition(|e| e.status == Status::Pending);
+        self.entries = rest;
+        pending.into_iter().map(|e| e.dm).collect()
+    }
+}
+
+// Where an entry stands, listed in front of the offer itself
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{} {} ago] #{:08x}",
+            self.status.colored(),
+            format_age(self.dm.age()),
+            self.dm.id
+        )
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use snd::{HostInfo, OfferedFile};
+    use std::time::SystemTime;
+
+    // An offer from 10.0.0.<host> of one file, which the sender waits on for expires_in
+    fn offer(host: u8, id: u32, file: &str, expires_in: Duration) -> DM {
+        let now = SystemTime::now();
+        DM {
+            id,
+            received: now,
+            expires: now + expires_in,
+            host_info: HostInfo {
+                name: format!("host{}", host),
+                ip: IpAddr::from([10, 0, 0, host]),
+                version: 1,
+            },
+            send_method: "windowed".to_string(),
+            streams: 1,
+            files: vec![OfferedFile {
+                path: file.to_string(),
+                file_type: "Binary file".to_string(),
+                size: 1,
+                digest: None,
+                streamed: false,
+            }],
+            sealed: false,
+            identity: None,
+        }
+    }
+
+    const WAIT: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn resent_offers_replace_the_waiting_copy() {
+        let mut inbox = Inbox::default();
+        inbox.add(offer(1, 7, "a.bin", WAIT));
+        inbox.add(offer(2, 8, "b.bin", WAIT));
+        let later = offer(1, 7, "a.bin", 2 * WAIT);
+        let expires = later.expires;
+        inbox.add(later);
+        assert_eq!(inbox.len(), 2);
+        assert_eq!(inbox.entries()[0].dm.expires, expires);
+
+        // The same files under a new id are the same offer, different files are a new one
+        inbox.add(offer(1, 9, "a.bin", WAIT));
+        assert_eq!(inbox.len(), 2);
+        assert_eq!(inbox.entries()[0].dm.id, 9);
+        inbox.add(offer(1, 10, "c.bin", WAIT));
+        assert_eq!(inbox.len(), 3);
+    }
+
+    #[test]
+    fn answered_offers_arent_replaced() {
+        let mut inbox = Inbox::default();
+        inbox.add(offer(1, 7, "a.bin", WAIT));
+        inbox.set_status(IpAddr::from([10, 0, 0, 1]), 7, Status::Completed);
+        inbox.add(offer(1, 7, "a.bin", WAIT));
+        assert_eq!(inbox.len(), 2);
+        assert!(inbox.entries()[1].status == Status::Pending);
+    }
+
+    #[test]
+    fn withdrawn_offers_go_unless_answered() {
+        let mut inbox = Inbox::default();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        inbox.add(offer(1, 7, "a.bin", WAIT));
+        inbox.add(offer(1, 8, "b.bin", WAIT));
+        inbox.set_status(ip, 8, Status::Accepted);
+        inbox.withdraw(ip, 7);
+        inbox.withdraw(ip, 8);
+        // The same id from another sender is a different offer
+        inbox.withdraw(IpAddr::from([10, 0, 0, 2]), 8);
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(inbox.entries()[0].dm.id, 8);
+    }
+
+    #[test]
+    fn offers_expire_when_the_sender_stops_waiting() {
+        let mut inbox = Inbox::default();
+        inbox.add(offer(1, 7, "a.bin", Duration::ZERO));
+        inbox.add(offer(1, 8, "b.bin", WAIT));
+        inbox.refresh();
+        assert!(inbox.entries()[0].status == Status::Expired);
+        assert!(inbox.entries()[1].status == Status::Pending);
+        // Expired offers stay listed for a while
+        assert_eq!(inbox.len(), 2);
+    }
+
+    #[test]
+    fn offers_are_found_by_sender_and_id() {
+        let mut inbox = Inbox::default();
+        inbox.add(offer(1, 7, "a.bin", WAIT));
+        inbox.add(offer(2, 7, "b.bin", WAIT));
+        inbox.add(offer(2, 8, "c.bin", WAIT));
+        let ip = IpAddr::from([10, 0, 0, 2]);
+        assert_eq!(inbox.find(ip, 7).unwrap().dm.files[0].path, "b.bin");
+        assert!(inbox.find(ip, 9).is_none());
+
+        // Still found once the indexes shift
+        inbox.remove(0);
+        inbox.set_status(ip, 8, Status::Accepted);
+        assert!(inbox.find(ip, 8).unwrap().status == Status::Accepted);
+        assert!(inbox.find(ip, 7).unwrap().status == Status::Pending);
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    fs::{read_to_string, remove_file, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+// How many newly received bytes have to pile up before the journal is written to disk again
+const SAVE_INTERVAL: u64 = 4 * 1024 * 1024;
+
+// Keeps track of which byte ranges of a .snd-partial file are already on disk, stored next to the
+// partial file in the same "key = value" format as the config
+pub struct Journal {
+    path: PathBuf,
+    // Where the download ends up once complete
+    destination: PathBuf,
+    source: String,
+    size: u64,
+    digest: String,
+    ranges: Vec<(u64, u64)>,
+    unsaved: u64,
+}
+
+impl Journal {
+    // Loads the journal belonging to a download. A journal that is missing, unreadable or written
+    // for another offer (different sender path, size or digest) is ignored and a fresh one is started
+    pub fn load(final_path: &Path, source: &str, size: u64, digest: Option<&str>) -> Journal {
+        let path = journal_path(final_path);
+        let mut journal = Journal {
+            path,
+            destination: final_path.to_path_buf(),
+            source: source.to_string(),
+            size,
+            digest: digest.unwrap_or_default().to_string(),
+            ranges: Vec::new(),
+            unsaved: 0,
+        };
+
+        let Ok(contents) = read_to_string(&journal.path) else {
+            return journal;
+        };
+        let mut matches = (false, false, false);
+        let mut ranges = Vec::new();
+        for line in contents.lines() {
+            if let Some(value) = line.strip_prefix("source = ") {
+                matches.0 = value.trim() == source;
+            } else if let Some(value) = line.strip_prefix("size = ") {
+                matches.1 = value.trim().parse::<u64>().ok() == Some(size);
+            } else if let Some(value) = line.strip_prefix("digest = ") {
+                matches.2 = value.trim() == journal.digest;
+            } else if let Some(value) = line.strip_prefix("range = ") {
+                let mut bounds = value.split_whitespace().map(|b| b.parse::<u64>());
+                if let (Some(Ok(start)), Some(Ok(end))) = (bounds.next(), bounds.next()) {
+                    ranges.push((start, end));
+                }
+            }
+        }
+        if matches == (true, true, true) {
+            for (start, end) in ranges {
+                journal.insert(start, end);
+            }
+        }
+
+        // Never trust more than what actually made it into the partial file
+        let on_disk = partial_path(final_path)
+            .metadata()
+            .map(|m| m.len())
+            .unwrap_or(0);
+        journal.ranges.retain_mut(|r| {
+            r.1 = r.1.min(on_disk);
+            r.0 < r.1
+        });
+        journal
+    }
+
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    // Where the sender can restart from, the end of the range that begins at byte 0
+    pub fn resume_offset(&self) -> u64 {
+        match self.ranges.first() {
+            Some(&(0, end)) => end,
+            _ => 0,
+        }
+    }
+
+    // Marks [start, end) as written, saving the journal every SAVE_INTERVAL bytes
+    pub fn record(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let before = self.covered();
+        self.insert(start, end);
+        self.unsaved += self.covered() - before;
+        if self.unsaved >= SAVE_INTERVAL
+            && let Err(e) = self.save()
+        {
+            status_err!("Failed to save transfer journal: {}", e);
+        }
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        let mut contents = format!(
+            "source = {}\nsize = {}\ndigest = {}\n",
+            self.source, self.size, self.digest
+        );
+        for (start, end) in &self.ranges {
+            contents.push_str(&format!("range = {} {}\n", start, end));
+        }
+        write(&self.path, contents)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    // Deletes the journal once the download is complete
+    pub fn remove(self) {
+        let _ = remove_file(&self.path);
+    }
+
+    fn covered(&self) -> u64 {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
+    }
+
+    fn insert(&mut self, start: u64, end: u64) {
+        self.ranges.push((start, end));
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.ranges.len());
+        for &(s, e) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+pub fn partial_path(final_path: &Path) -> PathBuf {
+    let mut name = final_path.as_os_str().to_owned();
+    name.push(".snd-partial");
+    PathBuf::from(name)
+}
+
+pub fn journal_path(final_path: &Path) -> PathBuf {
+    let mut name = final_path.as_os_str().to_owned();
+    name.push(".snd-journal");
+    PathBuf::from(name)
+}
+
+// Name a download is moved to when its checksum doesn't match the one in the offer
+pub fn corrupt_path(final_path: &Path) -> PathBuf {
+    let mut name = final_path.as_os_str().to_owned();
+    name.push(".snd-corrupt");
+    PathBuf::from(name)
+}
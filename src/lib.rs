@@ -0,0 +1,68 @@
+//! Send files to other machines on the local network.
+//!
+//! A [`Receiver`] announces itself on the network and hands out the offers senders make to it,
+//! a [`Sender`] finds receivers, offers them files and sends the ones they accept. Nothing is
+//! read from stdin and nothing is printed unless [`progress::set_console`] is turned on, progress
+//! is reported to the callbacks passed to [`Accepted::send`] and [`Receiver::accept`].
+//!
+//! ```no_run
+//! use snd::{Outgoing, Sender, SndError, types::Config};
+//! use std::time::Duration;
+//!
+//! fn send(path: &str, to: &str) -> Result<(), SndError> {
+//!     let host = {
+//!         let mut discovery = Sender::discover()?;
+//!         loop {
+//!             match discovery.next_host(Duration::from_secs(30))? {
+//!                 Some(host) if host.name == to => break host,
+//!                 Some(_) => continue,
+//!                 None => return Err(SndError::HostNotFound(to.to_string())),
+//!             }
+//!         }
+//!     };
+//!     let file = Outgoing::prepare(path.into(), false)?;
+//!     let offer = Sender::new(Config::default()).offer(&host, vec![file], Duration::from_secs(30))?;
+//!     let accepted = offer.wait()?;
+//!     accepted.send(|_, progress| println!("{} of {} bytes", progress.done, progress.total))
+//! }
+//! ```
+
+// println! and eprintln! for status messages, only printed when console output is on. With --json
+// stdout is kept for the summaries, so status messages go to stderr too
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::progress::console() {
+        } else if $crate::progress::json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! status_err {
+    ($($arg:tt)*) => {
+        if $crate::progress::console() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+mod archive;
+mod crypto;
+pub mod error;
+pub mod identity;
+mod journal;
+mod network;
+pub mod progress;
+mod protocol;
+mod receiver;
+mod sender;
+pub mod types;
+pub mod utils;
+
+pub use error::SndError;
+pub use progress::{Snapshot, Stats};
+pub use receiver::{Incoming, Receiver};
+pub use sender::{Accepted, Cancel, Discovery, Outgoing, PendingOffer, Sender};
+pub use types::{DM, HostInfo, OfferedFile};
@@ -1,14 +1,35 @@
-mod c;
+// println! for the status lines of headless sends and the daemon, on stderr with --json so stdout
+// only carries the summaries
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if snd::progress::json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
 mod cli;
+mod daemon;
+mod inbox;
 mod modes;
-mod network;
-mod types;
-mod utils;
+mod tui;
 
-use std::env;
+use colored::Colorize;
+use std::{env, process};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let parsed: String = cli::parse(&args[1..]);
-    println!("{}", parsed);
+    // The library stays quiet unless told otherwise, the binary wants its status messages
+    snd::progress::set_console(true);
+    match cli::parse(&args[1..]) {
+        // Nothing is left to say when a transfer finished, JSON output has no room for it
+        Ok(parsed) if parsed.is_empty() => {}
+        Ok(parsed) => println!("{}", parsed),
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            process::exit(e.exit_code());
+        }
+    }
 }
@@ -1,109 +1,48 @@
 use crate::{
-    c::{
-        diskman::du,
-        tui::{initTUI, runTUI, termTUI, update_tui_hostnames},
-    },
     cli::colored_rec_h,
-    network::{begin_broadcast_with_socket, send_file, send_to_ip, PORT},
-    types::{HostInfo, ShModes, DM},
-    utils::{
-        downloadfc, expand_path, extract_hostname, gen_cname, get_file_type, read_config, tarify, fpre
-    },
+    inbox::{Inbox, Status},
+    tui::{initTUI, runTUI, termTUI, update_tui_hostnames},
 };
 use colored::Colorize;
-use dirs::download_dir;
-use flate2::read::GzDecoder;
+use snd::{
+    DM, HostInfo, Incoming, Outgoing, Receiver, Sender, SndError,
+    identity::{TrustedPeer, fingerprint, read_trusted_peers, write_trusted_peers},
+    types::{Collision, ShModes},
+    utils::{expand_path, expand_paths, gen_cname, read_config},
+};
 use std::{
-    ffi::{c_char, CStr, CString},
-    fs::{remove_file, File},
+    ffi::{CStr, c_char},
     io::{self, Write},
-    net::{SocketAddr, UdpSocket},
-    //os::unix::fs::MetadataExt,
+    net::IpAddr,
     path::{Path, PathBuf},
+    process,
     sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tar::Archive;
-
-pub fn prompt(shtyp: ShModes, cname: String) {
-    if matches!(shtyp, ShModes::REC) {
-        let direct_messages: Arc<Mutex<Vec<DM>>> = Arc::new(Mutex::new(Vec::new()));
-        let direct_clone = Arc::clone(&direct_messages);
 
-        // Create main socket and set broadcast
-        let socket = UdpSocket::bind(("0.0.0.0", PORT)).expect("Failed to bind to port");
-        socket.set_broadcast(true).expect("Failed to set broadcast");
+// How long a headless send waits for the target host to show up
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
 
-        // Clone socket for broadcast thread
-        let broadcast_socket = socket.try_clone().expect("Failed to clone socket");
+// How long an offer waits for the receiver to accept it
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
 
-        // Start broadcast thread with cloned socket
-        thread::spawn(move || {
-            begin_broadcast_with_socket(&broadcast_socket);
-            loop {
-                thread::sleep(Duration::from_secs(2));
-                begin_broadcast_with_socket(&broadcast_socket);
-            }
-        });
+pub fn prompt(shtyp: ShModes, cname: String) -> Result<(), SndError> {
+    if matches!(shtyp, ShModes::REC) {
+        let direct_messages: Arc<Mutex<Inbox>> = Arc::new(Mutex::new(Inbox::default()));
+        let direct_clone = Arc::clone(&direct_messages);
 
-        let recv_socket = socket; // Use original socket for receiving due to errors with binding
-                                  // to the same addr multiple times
+        let receiver = Arc::new(Receiver::bind()?);
+        let listener = Arc::clone(&receiver);
         thread::spawn(move || {
-            let mut buf = [0; 1024];
             loop {
-                match recv_socket.recv_from(&mut buf) {
-                    Ok((size, source)) => {
-                        let message = String::from_utf8_lossy(&buf[..size]).to_string();
-                        if message.starts_with("DIRECTH: HMCHNE; ") {
-                            const PREFIX: &str = "DIRECTH: HMCHNE; ";
-                            let rest = &message[PREFIX.len()..].trim();
-                            let tokens: Vec<&str> = rest.split(';').map(|s| s.trim()).collect();
-                            let wfile_idx = tokens.iter().position(|&t| t == "WFILE");
-                            let wtyp_idx = tokens.iter().position(|&t| t == "WTYP");
-                            let wsz_idx = tokens.iter().position(|&t| t == "WSZ");
-                            let sndm_idx = tokens.iter().position(|&t| t == "SNDM");
-
-                            if let (
-                                Some(wfile_idx),
-                                Some(wtyp_idx),
-                                Some(wsz_idx),
-                                Some(sndm_idx),
-                            ) = (wfile_idx, wtyp_idx, wsz_idx, sndm_idx)
-                            {
-                                if wtyp_idx > wfile_idx
-                                    && wsz_idx > wtyp_idx
-                                    && sndm_idx > wsz_idx
-                                    && wtyp_idx < tokens.len()
-                                    && wsz_idx < tokens.len()
-                                    && sndm_idx < tokens.len()
-                                {
-                                    let hostname = tokens[0..wfile_idx].join("; ");
-                                    let file_path = tokens[wfile_idx + 1..wtyp_idx].join("; ");
-                                    let file_type = tokens[wtyp_idx + 1];
-                                    let file_size = tokens[wsz_idx + 1].parse::<u64>().unwrap_or(0);
-                                    let send_method = tokens[sndm_idx + 1].to_string();
-
-                                    // Add DM to list
-                                    let mut guard = direct_clone.lock().unwrap();
-                                    guard.push(DM {
-                                        host_info: HostInfo {
-                                            name: hostname,
-                                            ip: source.ip(),
-                                        },
-                                        file_path: file_path.to_string(),
-                                        file_type: file_type.to_string(),
-                                        file_size,
-                                        send_method,
-                                    });
-                                }
-                            } else {
-                                eprintln!("WFILE marker not found in message");
-                            }
-                        }
+                match listener.next_incoming() {
+                    // Add DM to list, resent offers replace the copy already there
+                    Ok(Incoming::Offer(dm)) => direct_clone.lock().unwrap().add(dm),
+                    Ok(Incoming::Withdrawn { ip, id }) => {
+                        direct_clone.lock().unwrap().withdraw(ip, id)
                     }
-                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                    Err(e) => eprintln!("Error receiving message: {}", e),
+                    Err(e) => eprintln!("{}", e),
                 }
             }
         });
@@ -115,27 +54,183 @@ pub fn prompt(shtyp: ShModes, cname: String) {
             res.clear();
             io::stdin()
                 .read_line(&mut res)
-                .expect("Failed to read line");
-            match res.trim() {
+                .map_err(SndError::io("Failed to read line"))?;
+            let line = res.trim();
+            let (cmd, arg) = line
+                .split_once(' ')
+                .map(|(c, a)| (c, a.trim()))
+                .unwrap_or((line, ""));
+            match cmd {
                 "exit" => break,
                 "help" => println!("{}", colored_rec_h()),
                 "vdms" => {
-                    let guard = direct_messages.lock().unwrap();
+                    let mut guard = direct_messages.lock().unwrap();
+                    guard.refresh();
                     if guard.is_empty() {
                         println!("No direct messages received yet.");
                     } else {
+                        let peers = read_trusted_peers();
                         println!("Direct Messages Received:");
-                        for (i, msg) in guard.iter().enumerate() {
-                            println!("{}. {}", i + 1, msg);
+                        for (i, entry) in guard.entries().iter().enumerate() {
+                            let label = trust_label(&entry.dm, &peers);
+                            println!("{}. {} {} {}", i + 1, entry, label, entry.dm);
                         }
                     }
                 }
-                "rec" => rec(direct_messages.clone()),
+                // A failed transfer shouldn't end the session, the other offers are still there
+                "rec" => {
+                    if let Err(e) = rec(direct_messages.clone(), &receiver, arg) {
+                        eprintln!("{} {}", "Error:".red().bold(), e);
+                    }
+                }
+                "reject" => reject(&receiver, &mut direct_messages.lock().unwrap(), arg),
+                "rejectall" => reject_all(&receiver, &mut direct_messages.lock().unwrap(), arg),
+                "trust" => trust(&direct_messages.lock().unwrap(), arg),
+                "untrust" => untrust(&direct_messages.lock().unwrap(), arg),
+                "trusted" => {
+                    let peers = read_trusted_peers();
+                    if peers.is_empty() {
+                        println!("No trusted peers yet.");
+                    }
+                    for peer in peers {
+                        println!("{} {}", peer.name.blue().bold(), fingerprint(&peer.key));
+                    }
+                }
                 _ => println!("{}", "Not a recognised command".red()),
             }
             print_prompt(&shtyp, &cname);
         }
     }
+    Ok(())
+}
+
+// How an offer shows up in vdms: who it is trusted as, or a warning if the sender can't be trusted
+fn trust_label(dm: &DM, peers: &[TrustedPeer]) -> String {
+    match &dm.identity {
+        Some(key) => match peers.iter().find(|p| &p.key == key) {
+            Some(peer) => format!("[trusted: {}]", peer.name).green().to_string(),
+            None => format!("[untrusted {}]", fingerprint(key))
+                .yellow()
+                .bold()
+                .to_string(),
+        },
+        None => "[unverified]".red().bold().to_string(),
+    }
+}
+
+fn parse_dm_index(arg: &str, len: usize) -> Option<usize> {
+    match arg.parse::<usize>() {
+        Ok(num) if num > 0 && num <= len => Some(num - 1),
+        _ => None,
+    }
+}
+
+// Turns down the offer at the given vdms index, anything after the index is passed on as the reason
+fn reject(receiver: &Receiver, inbox: &mut Inbox, arg: &str) {
+    let (index, reason) = arg
+        .split_once(' ')
+        .map(|(i, r)| (i, r.trim()))
+        .unwrap_or((arg, ""));
+    inbox.refresh();
+    let Some(idx) = parse_dm_index(index, inbox.len()) else {
+        println!(
+            "{}",
+            "Usage: reject <index of a received message> [reason]".red()
+        );
+        return;
+    };
+    let status = inbox.entries()[idx].status;
+    if status != Status::Pending {
+        println!("{} {}", "That offer is already".yellow(), status.colored());
+        return;
+    }
+    decline(receiver, &inbox.remove(idx), reason);
+}
+
+// Turns down every offer still waiting for an answer with the same reason
+fn reject_all(receiver: &Receiver, inbox: &mut Inbox, reason: &str) {
+    inbox.refresh();
+    let pending = inbox.take_pending();
+    if pending.is_empty() {
+        println!("No offers are waiting for an answer.");
+        return;
+    }
+    for dm in pending {
+        decline(receiver, &dm, reason);
+    }
+}
+
+// The offer is dropped from the list either way, a sender that misses the rejection still times out
+fn decline(receiver: &Receiver, dm: &DM, reason: &str) {
+    let reason = (!reason.is_empty()).then_some(reason);
+    match receiver.reject(dm, reason) {
+        Ok(()) => println!(
+            "{} {}",
+            "Rejected the offer from".yellow(),
+            dm.host_info.name.blue().bold()
+        ),
+        Err(e) => eprintln!("{} {}", "Error:".red().bold(), e),
+    }
+}
+
+// Trusts the device that signed the offer at the given vdms index
+fn trust(inbox: &Inbox, arg: &str) {
+    let Some(idx) = parse_dm_index(arg, inbox.len()) else {
+        println!("{}", "Usage: trust <index of a received message>".red());
+        return;
+    };
+    let dm = &inbox.entries()[idx].dm;
+    let Some(key) = &dm.identity else {
+        println!(
+            "{}",
+            "That offer isn't signed, there is no identity to trust".red()
+        );
+        return;
+    };
+    let mut peers = read_trusted_peers();
+    peers.retain(|p| &p.key != key);
+    peers.push(TrustedPeer {
+        name: dm.host_info.name.clone(),
+        key: key.clone(),
+    });
+    match write_trusted_peers(&peers) {
+        Ok(()) => println!(
+            "{} {} ({})",
+            "Trusted".green(),
+            dm.host_info.name.blue().bold(),
+            fingerprint(key)
+        ),
+        Err(e) => eprintln!("Failed to save trusted peers: {}", e),
+    }
+}
+
+// Removes trust from the sender of the offer at the given vdms index, or from a trusted peer
+// given by name or fingerprint
+fn untrust(inbox: &Inbox, arg: &str) {
+    let mut peers = read_trusted_peers();
+    let before = peers.len();
+    match parse_dm_index(arg, inbox.len()) {
+        Some(idx) => {
+            let key = inbox.entries()[idx].dm.identity.clone().unwrap_or_default();
+            peers.retain(|p| p.key != key);
+        }
+        None if !arg.is_empty() => peers.retain(|p| p.name != arg && fingerprint(&p.key) != arg),
+        None => {
+            println!(
+                "{}",
+                "Usage: untrust <index of a received message|name|fingerprint>".red()
+            );
+            return;
+        }
+    }
+    if peers.len() == before {
+        println!("{}", "No matching trusted peer".yellow());
+        return;
+    }
+    match write_trusted_peers(&peers) {
+        Ok(()) => println!("{}", "Peer is no longer trusted".green()),
+        Err(e) => eprintln!("Failed to save trusted peers: {}", e),
+    }
 }
 
 fn print_prompt(shtyp: &ShModes, cname: &str) {
@@ -150,41 +245,46 @@ fn print_prompt(shtyp: &ShModes, cname: &str) {
     let _ = io::stdout().flush();
 }
 
-fn snd_mode_tui() {
-    let mut res: String = String::new();
-    let mut valid: bool = false;
-    let mut exp: PathBuf = PathBuf::new();
+// Checks the paths and works out their sizes and checksums before anything is offered
+fn prepare_files(paths: Vec<PathBuf>) -> Result<Vec<Outgoing>, SndError> {
+    let follow_symlinks = read_config().follow_symlinks;
+    paths
+        .into_iter()
+        .map(|path| {
+            status!("{} is a valid file!", path.display());
+            status!("{}", "Calculating checksum...".yellow());
+            Outgoing::prepare(path, follow_symlinks)
+        })
+        .collect()
+}
 
-    while !valid {
+fn snd_mode_tui() -> Result<(), SndError> {
+    let mut res: String = String::new();
+    let paths = loop {
         res.clear();
         print_prompt(&ShModes::SND, &gen_cname());
-        print!(" Input a valid file path to send: ");
+        print!(" Input the paths or globs to send, separated by spaces: ");
         let _ = io::stdout().flush();
         io::stdin()
             .read_line(&mut res)
-            .expect("Failed to read line");
+            .map_err(SndError::io("Failed to read line"))?;
 
-        exp = expand_path(res.trim());
-        if exp.exists() {
-            valid = true;
+        // A single path with spaces in it is taken as is
+        let inputs: Vec<&str> = if expand_path(res.trim()).exists() {
+            vec![res.trim()]
         } else {
-            println!(
-                "{}",
-                "Provided full path does not exist. Please put in an existing file".red()
-            )
+            res.split_whitespace().collect()
+        };
+        match expand_paths(&inputs) {
+            Ok(paths) if !paths.is_empty() => break paths,
+            Ok(_) => println!("{}", "Please put in at least one path".red()),
+            Err(e) => println!("{}. {}", e.red(), "Please put in existing files".red()),
         }
-    }
-
-    let ftype = if exp.is_dir() {
-        let tar_path = tarify(exp.to_string_lossy().to_string());
-        exp = tar_path;
-        "directory".to_string()
-    } else {
-        get_file_type(&exp).to_string()
     };
+    let outgoing = prepare_files(paths)?;
 
-    let abspath: String = exp.to_string_lossy().to_string();
-    println!("{} is a valid file at {}!", res.trim(), abspath);
+    // Bound before the TUI takes over the terminal so a busy port can still be reported
+    let mut discovery = Sender::discover()?;
 
     unsafe {
         initTUI();
@@ -194,43 +294,18 @@ fn snd_mode_tui() {
     let hostnames: Arc<Mutex<Vec<HostInfo>>> = Arc::new(Mutex::new(Vec::new()));
     let hostnames_clone = Arc::clone(&hostnames);
 
-    // Start UDP listener thread with stop flag
+    // Start discovery thread with stop flag
     let stop_flag_clone = Arc::clone(&stop_flag);
     let handle = thread::spawn(move || {
-        let socket = UdpSocket::bind(("0.0.0.0", PORT)).expect("Failed to bind to port");
-        socket
-            .set_read_timeout(Some(Duration::from_millis(100)))
-            .expect("Failed to set read timeout");
-        let mut buf = [0; 1024];
-        loop {
-            // Check stop flag
-            if *stop_flag_clone.lock().unwrap() {
-                break;
-            }
-
-            match socket.recv_from(&mut buf) {
-                Ok((size, source)) => {
-                    let message = String::from_utf8_lossy(&buf[..size]);
-                    let hostname = extract_hostname(&message);
+        while !*stop_flag_clone.lock().unwrap() {
+            match discovery.next_host(Duration::from_millis(100)) {
+                Ok(Some(host)) => {
                     let mut guard = hostnames_clone.lock().unwrap();
-
-                    if !guard.iter().any(|h| h.name == hostname) {
-                        guard.push(HostInfo {
-                            name: hostname.clone(),
-                            ip: source.ip(),
-                        });
-
-                        let names: Vec<String> = guard.iter().map(|h| h.name.clone()).collect();
-                        update_tui_hostnames(&names);
-                    }
-                }
-                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue, // Handle EINTR
-                Err(e)
-                    if e.kind() == io::ErrorKind::WouldBlock
-                        || e.kind() == io::ErrorKind::TimedOut =>
-                {
-                    continue;
+                    guard.push(host);
+                    let names: Vec<String> = guard.iter().map(|h| h.name.clone()).collect();
+                    update_tui_hostnames(&names);
                 }
+                Ok(None) => {}
                 Err(e) => eprintln!("Receive error: {}", e),
             }
         }
@@ -242,285 +317,254 @@ fn snd_mode_tui() {
         .unwrap_or("Failed to get result from runTUI")
         .to_string();
 
-    // Stop listener thread
+    // Stop discovery thread
     *stop_flag.lock().unwrap() = true;
-    handle.join().expect("Failed to join listener thread");
+    if handle.join().is_err() {
+        eprintln!("{}", "The discovery thread panicked".red());
+    }
 
-    let target_ip = {
-        let guard = hostnames.lock().unwrap();
-        guard
-            .iter()
-            .find(|h| h.name == thnms)
-            .map(|h| h.ip)
-            .expect("Host not found")
+    unsafe {
+        termTUI();
+    }
+
+    let guard = hostnames.lock().unwrap();
+    let host = guard
+        .iter()
+        .find(|h| h.name == thnms)
+        .ok_or(SndError::HostNotFound(thnms))?;
+    offer_and_send(host, outgoing, true)
+}
+
+// Headless send for scripts: the paths and host come from the command line, the host is waited for
+// until it shows up in discovery and nothing is ever asked on stdin
+pub fn snd_headless(paths: &[&str], to: &str) -> Result<(), SndError> {
+    let outgoing = prepare_files(expand_paths(paths).map_err(SndError::InvalidPath)?)?;
+
+    status!("{} {}", "Waiting for".yellow(), to.blue().bold());
+    let mut discovery = Sender::discover()?;
+    let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+    let host = loop {
+        match discovery.next_host(deadline.saturating_duration_since(Instant::now()))? {
+            Some(host) if host.name == to || host.ip.to_string() == to => break host,
+            Some(_) => continue,
+            None => return Err(SndError::HostNotFound(to.to_string())),
+        }
     };
+    // The offer needs the port discovery is holding
+    drop(discovery);
+    offer_and_send(&host, outgoing, false)
+}
 
-    println!("{}", target_ip);
-    send_to_ip(
-        target_ip,
-        format!(
-            "DIRECTH: HMCHNE; {}; WFILE; {}; WTYP; {}; WSZ; {}; SNDM; {}",
-            gen_cname(),
-            abspath,
-            ftype,
-            //std::fs::metadata(Path::new(&abspath)).unwrap().size(),
-            unsafe {
-                du(
-                    CString::new(abspath.as_str())
-                        .expect("Failed to convert to CString")
-                        .as_ptr(),
-                    read_config().follow_symlinks,
-                )
-            }, // Jesus christ this took forever to work out
-            read_config().send_method,
-        ),
+// Offers the files to the target and sends the ones it accepts. When interactive the user confirms
+// the transfer first, otherwise it starts as soon as the receiver accepts
+fn offer_and_send(
+    host: &HostInfo,
+    outgoing: Vec<Outgoing>,
+    interactive: bool,
+) -> Result<(), SndError> {
+    status!("{}", host.ip);
+    let pending = Sender::new(read_config()).offer(host, outgoing, ACCEPT_TIMEOUT)?;
+    // Ctrl-C withdraws the offer or stops the transfer so the receiver isn't left waiting, a
+    // second one quits straight away
+    let cancel = pending.cancel_handle();
+    let handler = cancel.clone();
+    let _ = ctrlc::set_handler(move || {
+        if handler.is_cancelled() {
+            process::exit(130);
+        }
+        eprintln!("{}", "Cancelling...".yellow());
+        handler.cancel();
+    });
+    if let Some(code) = pending.pairing_code() {
+        status!("{} {}", "Pairing code:".yellow().bold(), code.blue().bold());
+        status!("{}", "The receiver has to type it in to accept".yellow());
+    }
+    status!("{}", "Waiting for receiver to accept...".yellow());
+    let accepted = pending.wait()?;
+
+    status!(
+        "{} {} {}",
+        "Receiver".green(),
+        accepted.receiver().blue().bold(),
+        "accepted:".green(),
     );
-
-    unsafe {
-        termTUI();
+    for (file, _) in accepted.files() {
+        status!("  {}", file.abspath.blue().bold());
     }
-    println!("{}", "Waiting for receiver to accept...".yellow());
-    let socket = UdpSocket::bind(("0.0.0.0", PORT)).expect("Failed to bind");
-    socket
-        .set_read_timeout(Some(Duration::from_secs(30)))
-        .expect("Failed to set timeout");
-
-    let mut buf = [0; 1024];
-    let mut accepted = false;
-
-    while !accepted {
-        match socket.recv_from(&mut buf) {
-            Ok((size, source)) => {
-                let message = String::from_utf8_lossy(&buf[..size]);
-                if message.starts_with("ACCEPT: ") {
-                    let file_path = message["ACCEPT: ".len()..]
-                        .split(';')
-                        .next()
-                        .unwrap_or("")
-                        .trim();
-                    let from_host = message.split("FROM: ").nth(1).unwrap_or("").trim();
-
-                    if file_path == abspath {
-                        println!(
-                            "{} {} {} {} {}",
-                            "Receiver".green(),
-                            from_host.blue().bold(),
-                            "accepted".green(),
-                            "file:".green(),
-                            file_path.blue().bold()
-                        );
-
-                        // Prompt for confirmation
-                        print!("Send file? (y/N): ");
-                        io::stdout().flush().unwrap();
-
-                        let mut response = String::new();
-                        io::stdin().read_line(&mut response).unwrap();
-
-                        if response.trim().eq_ignore_ascii_case("y") {
-                            if let Err(e) = socket.send_to("FSNT;".as_bytes(), source) {
-                                eprintln!("Failed to send FSNT;: {}", e);
-                            } else {
-                                println!("Sent FSNT; to {}", source);
-                            }
-                            send_file(
-                                File::open(file_path).expect("Failed to open file"),
-                                source,
-                                read_config().send_method,
-                            );
-                            if get_file_type(Path::new(&abspath)) == "directory" {
-                                remove_file(&exp).expect("Failed to remove temporary tarball")
-                            }
-                        } else {
-                            println!("{}", "Transfer canceled".yellow());
-                        }
 
-                        accepted = true;
-                    }
-                }
-            }
-            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue, // Handle EINTR
-            Err(e)
-                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
-            {
-                println!("{}", "No acceptance received. Timing out...".yellow());
-                break;
-            }
-            Err(e) => {
-                eprintln!("Receive error: {}", e);
-                break;
-            }
+    if interactive {
+        // Prompt for confirmation
+        print!("Send file? (y/N): ");
+        let _ = io::stdout().flush();
+
+        let mut response = String::new();
+        io::stdin()
+            .read_line(&mut response)
+            .map_err(SndError::io("Failed to read line"))?;
+
+        if !response.trim().eq_ignore_ascii_case("y") || cancel.is_cancelled() {
+            accepted.abort();
+            println!("{}", "Transfer canceled".yellow());
+            return Ok(());
         }
     }
+
+    // The progress bar is drawn by the library itself while console output is on
+    accepted.send(|_, _| {})
 }
 
-pub fn sh_init(shtyp: ShModes) {
+pub fn sh_init(shtyp: ShModes) -> Result<(), SndError> {
     match shtyp {
         ShModes::REC => prompt(shtyp, gen_cname()),
         ShModes::SND => snd_mode_tui(),
     }
 }
 
-fn rec(dms: Arc<Mutex<Vec<DM>>>) {
-    let guard = dms.lock().unwrap();
-    if guard.is_empty() {
+// Accepts an offer, either the one named by "rec <index> [destination]" or one picked from the list.
+// The index is turned into the offer's id straight away, and the inbox is only locked to look
+// things up so offers keep coming in during the transfer
+fn rec(dms: Arc<Mutex<Inbox>>, receiver: &Receiver, arg: &str) -> Result<(), SndError> {
+    let (index, dest) = arg
+        .split_once(' ')
+        .map(|(i, d)| (i, d.trim()))
+        .unwrap_or((arg, ""));
+    // expand_path keeps a trailing slash, which is what marks the destination as a folder
+    let dest = (!dest.is_empty()).then(|| expand_path(dest));
+
+    // The offers as they were listed or as the index was typed, in that order
+    let listed: Vec<(IpAddr, u32)> = {
+        let mut guard = dms.lock().unwrap();
+        guard.refresh();
+        if index.is_empty() && !guard.is_empty() {
+            println!("Direct Messages Received:");
+            for (i, entry) in guard.entries().iter().enumerate() {
+                println!("{}. {} {}", i + 1, entry, entry.dm);
+            }
+        }
+        guard
+            .entries()
+            .iter()
+            .map(|e| (e.dm.host_info.ip, e.dm.id))
+            .collect()
+    };
+    if listed.is_empty() {
         println!("No direct messages received yet.");
-        return;
-    }
-
-    println!("Direct Messages Received:");
-    for (i, msg) in guard.iter().enumerate() {
-        println!("{}. {}", i + 1, msg);
+        return Ok(());
     }
 
-    println!("Type the index of the message you would like to accept (or 'cancel' to cancel)");
-    let _ = io::stdout().flush();
-
-    let mut res = String::new();
-    io::stdin()
-        .read_line(&mut res)
-        .expect("Failed to read line");
-    let res = res.trim();
+    let idx = if index.is_empty() {
+        println!("Type the index of the message you would like to accept (or 'cancel' to cancel)");
+        let _ = io::stdout().flush();
 
-    if res.eq_ignore_ascii_case("cancel") {
-        println!("{}", "Acceptance canceled".yellow());
-        return;
-    }
+        let mut res = String::new();
+        io::stdin()
+            .read_line(&mut res)
+            .map_err(SndError::io("Failed to read line"))?;
+        let res = res.trim();
 
-    let idx = match res.parse::<usize>() {
-        Ok(num) if num > 0 && num <= guard.len() => num - 1,
-        _ => {
-            println!("{}", "Invalid index. Please enter a valid number".red());
-            return;
+        if res.eq_ignore_ascii_case("cancel") {
+            println!("{}", "Acceptance canceled".yellow());
+            return Ok(());
         }
+        parse_dm_index(res, listed.len())
+    } else {
+        parse_dm_index(index, listed.len())
+    };
+    let Some(idx) = idx else {
+        println!("{}", "Invalid index. Please enter a valid number".red());
+        return Ok(());
+    };
+    let (ip, id) = listed[idx];
+
+    // Accepted offers can be tried again, say after a mistyped pairing code
+    let dm = {
+        let mut guard = dms.lock().unwrap();
+        guard.refresh();
+        let Some(entry) = guard.find(ip, id) else {
+            println!("{}", "That offer is no longer listed".yellow());
+            return Ok(());
+        };
+        if matches!(entry.status, Status::Completed | Status::Expired) {
+            println!("{} {}", "That offer is".yellow(), entry.status.colored());
+            return Ok(());
+        }
+        entry.dm.clone()
     };
-
-    let dm = &guard[idx];
     println!("{}: {}", "You selected".green(), dm);
-
-    let socket = UdpSocket::bind(("0.0.0.0", 0)).expect("Failed to bind");
-    let target = SocketAddr::new(dm.host_info.ip, PORT);
-    let msg = format!("ACCEPT: {}; FROM: {}", dm.file_path, gen_cname());
-
-    if let Err(e) = socket.send_to(msg.as_bytes(), target) {
-        eprintln!("Failed to send acceptance: {}", e);
+    // Encrypted offers only show their files once paired, which needs the code from the sender
+    let code = if dm.sealed {
+        print!("Enter the pairing code shown on the sender: ");
+        let _ = io::stdout().flush();
+        let mut code = String::new();
+        io::stdin()
+            .read_line(&mut code)
+            .map_err(SndError::io("Failed to read line"))?;
+        Some(code)
     } else {
-        println!(
-            "{} {} {} {} {} {}",
-            "Request sent to".green(),
-            dm.host_info.name.blue().bold(),
-            "to send".green(),
-            dm.file_path.blue().bold(),
-            "using mode".green(),
-            dm.send_method.blue().bold(),
-        );
-    }
-    let mut buf = [0; 1400];
-    match socket.recv_from(&mut buf) {
-        Ok((size, _)) => {
-            let msg = String::from_utf8_lossy(&buf[..size]).to_string();
-            if msg.trim() == "FSNT;" {
-                println!(
-                    "{} {}",
-                    "File being sent through".green(),
-                    dm.send_method.blue()
-                );
-                let (mut fp, saved_path) = downloadfc(&Path::new(&dm.file_path));
-                let mut size_buf = [0u8; 8];
-                socket
-                    .recv_from(&mut size_buf)
-                    .expect("Failed to receive file size");
-                let file_size = u64::from_be_bytes(size_buf);
-                let mut remaining = file_size;
-                let mut chunk_buf = [0u8; 1500];
-
-                let mut next_expected_seq = 0;
-
-                while remaining > 0 {
-                    let (count, src) = socket
-                        .recv_from(&mut chunk_buf)
-                        .expect("Failed to receive chunk");
-
-                    let (seq_num, data) = if dm.send_method == "semi-reliable" {
-                        if count < 8 {
-                            eprintln!("Packet too small, skipping");
-                            continue;
-                        }
-                        let seq_bytes = &chunk_buf[0..8];
-                        let seq_num = u64::from_be_bytes(seq_bytes.try_into().unwrap());
-                        (seq_num, &chunk_buf[8..count])
-                    } else {
-                        (0, &chunk_buf[..count])
-                    };
-
-                    if dm.send_method == "semi-reliable" {
-                        // Skip duplicate packets
-                        if seq_num < next_expected_seq {
-                            // Still ACK duplicates to prevent retries
-                            let ack = seq_num.to_be_bytes();
-                            if let Err(e) = socket.send_to(&ack, src) {
-                                eprintln!("Failed to send ACK: {}", e);
-                            }
-                            continue;
-                        }
-
-                        // Skip out-of-order packets
-                        if seq_num != next_expected_seq {
-                            eprintln!(
-                                "Out-of-order packet: expected {}, got {}",
-                                next_expected_seq, seq_num
-                            );
-                            continue;
-                        }
-                    }
-
-                    let data_len = data.len();
-                    if data_len > 0 {
-                        let write_size = std::cmp::min(remaining, data_len as u64) as usize;
-                        fp.write_all(&data[..write_size])
-                            .expect("Failed to write chunk");
-                        remaining -= write_size as u64;
-                    }
-
-                    if dm.send_method == "semi-reliable" {
-                        next_expected_seq += 1;
+        None
+    };
+    let policy = Collision::from_config(&read_config().on_collision);
+    let result = receiver.accept(
+        &dm,
+        code.as_deref(),
+        dest.as_deref(),
+        choose_files,
+        |path| policy.unwrap_or_else(|| ask_collision(path)),
+        |_, _| {},
+    );
+    let status = match result {
+        Ok(()) => Status::Completed,
+        Err(_) => Status::Accepted,
+    };
+    dms.lock().unwrap().set_status(ip, id, status);
+    result
+}
 
-                        // Send ACK with sequence number
-                        let ack = seq_num.to_be_bytes();
-                        if let Err(e) = socket.send_to(&ack, src) {
-                            eprintln!("Failed to send ACK: {}", e);
-                        }
-                    }
+// Asks what to do about a file that is already there, for the "prompt" collision policy
+fn ask_collision(path: &Path) -> Collision {
+    print!(
+        "{} (r)ename, (o)verwrite or (s)kip? [r]: ",
+        format!("{} already exists.", path.display()).yellow()
+    );
+    let _ = io::stdout().flush();
+    let mut res = String::new();
+    // Without an answer leave the existing file alone
+    if io::stdin().read_line(&mut res).is_err() {
+        return Collision::Skip;
+    }
+    match res.trim().to_lowercase().as_str() {
+        "o" | "overwrite" => Collision::Overwrite,
+        "s" | "skip" => Collision::Skip,
+        _ => Collision::Rename,
+    }
+}
 
-                    if remaining == 0 {
-                        break;
-                    }
-                }
-                fp.flush().expect("Failed to flush file");
-                drop(fp);
-                if dm.file_type == "directory" {
-                    let file = File::open(&saved_path).expect("Failed to open tar archive");
-                    let tar = GzDecoder::new(file);
-                    let mut archive = Archive::new(tar);
-                    let sname: String = fpre(&saved_path).unwrap_or_default().to_string_lossy().to_string();
-                    let sfpth = format!("{}/{}", download_dir().unwrap_or_default().to_string_lossy().to_string(), sname);
-                    std::fs::create_dir(&sfpth).expect("Failed to create dir to unpack the tar into");
-                    if let Err(e) = archive.unpack(sfpth) {
-                        eprintln!("Failed to unpack tar archive: {}", e);
-                        eprintln!("The tar file is located at: {}", saved_path.display());
-                    } else {
-                        remove_file(&saved_path).expect("Failed to remove tar archive");
-                    }
+// Asks which files of a multi-file offer to take, all of them unless told otherwise
+fn choose_files(dm: &DM) -> Vec<usize> {
+    if dm.files.len() <= 1 {
+        return (0..dm.files.len()).collect();
+    }
+    print!("Numbers of the files to accept separated by commas (or press enter for all): ");
+    let _ = io::stdout().flush();
+    let mut res = String::new();
+    // Nothing to go on without an answer, take none rather than guess
+    if io::stdin().read_line(&mut res).is_err() {
+        return Vec::new();
+    }
+    if res.trim().is_empty() || res.trim().eq_ignore_ascii_case("all") {
+        return (0..dm.files.len()).collect();
+    }
+    let mut chosen = Vec::new();
+    for part in res.split(',') {
+        match part.trim().parse::<usize>() {
+            Ok(num) if num > 0 && num <= dm.files.len() => {
+                if !chosen.contains(&(num - 1)) {
+                    chosen.push(num - 1);
                 }
             }
+            _ => println!("{} {}", "Ignoring invalid file number".red(), part.trim()),
         }
-        Err(e) => eprintln!("Receive error: {}", e),
-    }
-    if dm.file_type == "directory" {
-        let tar_gz = File::open(&dm.file_path).expect("Failed to open tar archive");
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        archive.unpack(".").expect("Failed to unpack tar archive");
-        let _ = remove_file(&dm.file_path).expect("Failed to remove tar archive");
     }
+    chosen.sort_unstable();
+    chosen
 }
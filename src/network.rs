@@ -1,177 +1,1510 @@
-use crate::utils::{gen_cname, is_vpn};
+use crate::{
+    crypto::{SEAL_OVERHEAD, Session},
+    error::SndError,
+    progress::{Progress, Stats, Watch},
+    protocol::{DATA_OVERHEAD, Link, MIN_VERSION, Message, VERSION, read_frame},
+    utils::{gen_cname, human_readable_size, is_vpn, read_config},
+};
 use colored::Colorize;
 use if_addrs::IfAddr;
 use std::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
     fs::File,
-    io::{ErrorKind, Read, Seek, SeekFrom},
-    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
-    time::Duration,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::unix::fs::FileExt,
+    thread,
+    time::{Duration, Instant},
 };
 
 pub const PORT: u16 = 58422;
+// Big enough for any UDP datagram, offers listing many files can get large
+pub const MAX_DATAGRAM: usize = 65536;
+// Most a single UDP datagram over IPv4 can carry
+pub const MAX_PAYLOAD: usize = 65507;
+// Start of the range and total file length at the front of every parallel stream
+const RANGE_HEADER_SIZE: usize = 16;
+// How far past the oldest unacknowledged packet the windowed mode sends. The receiver uses the
+// same value to bound how far ahead of the next expected packet it is willing to buffer.
+pub const WINDOW_SIZE: u64 = 64;
+// Most file data a TCP transfer writes or reads at once, each sealed chunk holds at most this much
+const TCP_CHUNK: usize = 64 * 1024;
+// How long a receiver keeps answering retransmissions after it has everything, in case the
+// sender didn't hear the last acks
+const LINGER: Duration = Duration::from_secs(1);
+// How long a sender waits for the receiver to answer a Start before sending it again
+const START_RESEND: Duration = Duration::from_millis(500);
+// What receivers report as missing when the sender never even sent the size
+const NOTHING_RECEIVED: u64 = u64::MAX;
+
+// When either side gives up on the other, from the config
+struct Limits {
+    max_retries: u64,
+    idle_timeout: Duration,
+}
 
-pub fn send_to_ip(ip: IpAddr, msg: String) {
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind to a port");
-    let target = SocketAddr::new(ip, PORT);
-    match socket.send_to(msg.as_bytes(), target) {
-        Ok(_) => println!("Sent to {}", target),
-        Err(e) => eprintln!("Failed to send to {}: {}", target, e),
+fn limits() -> Limits {
+    let config = read_config();
+    Limits {
+        max_retries: config.max_retries,
+        idle_timeout: Duration::from_secs(config.idle_timeout),
     }
 }
 
+// How long either side goes without hearing from the other before giving up on it
+pub fn idle_timeout() -> Duration {
+    limits().idle_timeout
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
 pub fn begin_broadcast_with_socket(socket: &UdpSocket) {
     let mut sent = false;
-    let interfaces = if_addrs::get_if_addrs().expect("Failed to get if addrs");
+    // Without the interface list there is still the fallback broadcast below
+    let interfaces = if_addrs::get_if_addrs().unwrap_or_else(|e| {
+        status_err!("Failed to get network interfaces: {}", e);
+        Vec::new()
+    });
 
     for interface in interfaces {
         if interface.is_loopback() || is_vpn(&interface.name) {
             continue;
         }
-        if let IfAddr::V4(addr) = interface.addr {
-            if let Some(broadcast) = addr.broadcast {
-                let target = SocketAddr::new(IpAddr::V4(broadcast), PORT);
-                let msg = "Hello from ".to_string() + &gen_cname() + "!";
-                match socket.send_to(msg.as_bytes(), &target) {
-                    Ok(_) => sent = true,
-                    Err(e) => eprintln!("Failed to send via {}: {}", interface.name, e),
-                }
+        if let IfAddr::V4(addr) = interface.addr
+            && let Some(broadcast) = addr.broadcast
+        {
+            let target = SocketAddr::new(IpAddr::V4(broadcast), PORT);
+            match socket.send_to(&hello(), target) {
+                Ok(_) => sent = true,
+                Err(e) => status_err!("Failed to send via {}: {}", interface.name, e),
             }
         }
     }
 
     if !sent {
-        eprintln!(
+        status_err!(
             "{}",
             "No valid interfaces found. Trying fallback broadcast...".red()
         );
         let fallback = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)), PORT);
-        if let Err(e) = socket.send_to("Hello, world!".as_bytes(), fallback) {
-            eprintln!("Fallback broadcast failed: {}", e);
+        if let Err(e) = socket.send_to(&hello(), fallback) {
+            status_err!("Fallback broadcast failed: {}", e);
         }
     }
 }
 
-pub fn send_file(file: File, target: SocketAddr, mode: String) {
-    if mode == "semi-reliable" {
-        send_file_semi_reliable(file, target);
+// Always encoded in the newest version, every version can read a Hello
+fn hello() -> Vec<u8> {
+    Message::Hello {
+        name: gen_cname(),
+        min_version: MIN_VERSION,
+        max_version: VERSION,
+    }
+    .encode(VERSION)
+}
+
+// Announces this machine every couple of seconds so senders can find it
+pub fn spawn_broadcast_thread(socket: UdpSocket) {
+    thread::spawn(move || {
+        begin_broadcast_with_socket(&socket);
+        loop {
+            thread::sleep(Duration::from_secs(2));
+            begin_broadcast_with_socket(&socket);
+        }
+    });
+}
+
+pub fn send_file(
+    mut file: File,
+    target: SocketAddr,
+    mode: String,
+    offset: u64,
+    link: Link,
+    watch: Watch,
+) -> Result<Stats, SndError> {
+    // Skip what the receiver already has from an earlier, interrupted transfer
+    let size = file
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| remaining_size(&mut file))
+        .map_err(SndError::io("Failed to seek file"))?;
+    send_stream(file, size, target, &mode, link, watch)
+}
+
+// Sends `size` bytes read from any source, used directly for data that isn't a file on disk such
+// as a directory being tarred on the fly
+pub fn send_stream(
+    source: impl Read,
+    size: u64,
+    target: SocketAddr,
+    mode: &str,
+    link: Link,
+    watch: Watch,
+) -> Result<Stats, SndError> {
+    let progress = Progress::sending(size, watch);
+    let complete = match mode {
+        "semi-reliable" => send_file_semi_reliable(source, size, target, link, &progress),
+        "windowed" => send_windowed(source, size, target, link, &progress),
+        "tcp" => send_file_tcp(source, size, target, link, &progress),
+        _ => send_file_legacy(source, size, target, link, &progress),
+    };
+    // Clear the bar before anything else is printed, errors included
+    let stats = progress.finish(matches!(complete, Ok(true)));
+    complete?;
+    report_sent(&stats);
+    Ok(stats)
+}
+
+fn report_sent(stats: &Stats) {
+    if stats.complete {
+        status!("{}", "File transfer complete!".green());
     } else {
-        send_file_legacy(file, target);
+        status_err!(
+            "{} {} {} {}",
+            "Transfer aborted after".red(),
+            human_readable_size(stats.bytes),
+            "of".red(),
+            human_readable_size(stats.total)
+        );
     }
 }
 
-fn send_file_legacy(mut file: File, target: SocketAddr) {
-    let mut buf = [0; 1400];
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind to a port");
+// Reads until the buffer is full or the source runs out, sources like pipes hand data over in
+// smaller pieces than a packet holds
+fn read_chunk(source: &mut impl Read, buf: &mut [u8]) -> Result<usize, SndError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(SndError::Io("Failed to read file chunk".to_string(), e)),
+        }
+    }
+    Ok(filled)
+}
 
-    let file_size = file.metadata().expect("Failed to get metadata").len();
-    let size_bytes = file_size.to_be_bytes();
+fn send_file_legacy(
+    mut file: impl Read,
+    file_size: u64,
+    target: SocketAddr,
+    link: Link,
+    progress: &Progress,
+) -> Result<bool, SndError> {
+    let mut buf = [0; 1400];
+    let socket =
+        UdpSocket::bind("0.0.0.0:0").map_err(SndError::network("Failed to bind to a port"))?;
 
     // Send file size first
-    socket
-        .send_to(&size_bytes, &target)
-        .expect("Failed to send file size");
+    link.send(&socket, &Message::Size(file_size), target)
+        .map_err(SndError::network("Failed to send file size"))?;
 
     // Process file in chunks
-    loop {
-        let bytes_read = file.read(&mut buf).expect("Failed to read file");
+    let mut sent = 0;
+    for seq in 0.. {
+        if link.cancelled() {
+            return Err(abort_stream(&socket, target, link));
+        }
+        let bytes_read = read_chunk(&mut file, &mut buf)?;
         if bytes_read == 0 {
             break;
         }
-        socket
-            .send_to(&buf[..bytes_read], &target)
-            .expect("Failed to send data chunk");
+        let data = buf[..bytes_read].to_vec();
+        link.send(&socket, &Message::Data { seq, data }, target)
+            .map_err(SndError::network("Failed to send data chunk"))?;
+        progress.packet();
+        progress.add(bytes_read as u64);
+        sent += bytes_read as u64;
+    }
+    end_stream(&socket, target, link);
+    // Nothing comes back in legacy mode, whatever was sent counts as delivered
+    Ok(sent == file_size)
+}
+
+// Round trip time estimation as in RFC 6298, the retransmission timeout follows the measured
+// latency instead of a fixed guess
+struct RttEstimator {
+    srtt: Option<f64>,
+    rttvar: f64,
+    rto: f64,
+}
+
+impl RttEstimator {
+    // All in milliseconds
+    const INITIAL: f64 = 100.0;
+    const MIN: f64 = 2.0;
+    const MAX: f64 = 2000.0;
+    const GRANULARITY: f64 = 1.0;
+
+    fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: 0.0,
+            rto: Self::INITIAL,
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        let r = rtt.as_secs_f64() * 1000.0;
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = r / 2.0;
+                r
+            }
+            Some(srtt) => {
+                self.rttvar = 0.75 * self.rttvar + 0.25 * (srtt - r).abs();
+                0.875 * srtt + 0.125 * r
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + (4.0 * self.rttvar).max(Self::GRANULARITY)).clamp(Self::MIN, Self::MAX);
+    }
+
+    // Backs off after a timeout, until the next sample
+    fn backoff(&mut self) {
+        self.rto = (self.rto * 2.0).min(Self::MAX);
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.rto / 1000.0)
     }
+}
 
-    println!("{}", "File transfer complete!".green());
+// Additive increase, multiplicative decrease: the window grows by a packet per ack in slow start
+// and by about one packet per round trip after that, and halves whenever a packet is lost
+struct CongestionWindow {
+    cwnd: f64,
+    ssthresh: f64,
 }
 
-fn send_file_semi_reliable(mut file: File, target: SocketAddr) {
+impl CongestionWindow {
+    const INITIAL: f64 = 4.0;
+    const MIN: f64 = 1.0;
+
+    fn new() -> CongestionWindow {
+        CongestionWindow {
+            cwnd: Self::INITIAL,
+            ssthresh: WINDOW_SIZE as f64,
+        }
+    }
+
+    fn on_ack(&mut self) {
+        self.cwnd += if self.cwnd < self.ssthresh {
+            1.0
+        } else {
+            1.0 / self.cwnd
+        };
+        self.cwnd = self.cwnd.min(WINDOW_SIZE as f64);
+    }
+
+    fn on_loss(&mut self) {
+        self.ssthresh = (self.cwnd / 2.0).max(Self::MIN);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn size(&self) -> usize {
+        self.cwnd as usize
+    }
+}
+
+// A packet of the semi-reliable window, sent_at is None until it has been sent
+struct Unacked {
+    seq: u64,
+    packet: Vec<u8>,
+    sent_at: Option<Instant>,
+    // Round trips of retransmitted packets are ambiguous and not sampled (Karn's algorithm)
+    retransmitted: bool,
+}
+
+// Go-back-N: the receiver only takes packets in order and acks each one, so an ack covers every
+// packet up to it and everything after a lost packet has to be sent again. How many packets are
+// in flight is up to the congestion window, how long to wait for an ack up to the RTT estimate
+fn send_file_semi_reliable(
+    mut file: impl Read,
+    file_size: u64,
+    target: SocketAddr,
+    link: Link,
+    progress: &Progress,
+) -> Result<bool, SndError> {
     const CHUNK_SIZE: usize = 1392;
-    const INITIAL_TIMEOUT: u64 = 100;
-    const MAX_TIMEOUT: u64 = 2000;
 
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind to a port");
+    let limits = limits();
+    let socket =
+        UdpSocket::bind("0.0.0.0:0").map_err(SndError::network("Failed to bind to a port"))?;
     socket
         .set_nonblocking(false)
-        .expect("Failed to set blocking");
-    // Removed initial timeout setting here
-
-    let file_size = file.metadata().expect("Failed to get metadata").len();
-    let size_bytes = file_size.to_be_bytes();
+        .map_err(SndError::network("Failed to set blocking"))?;
 
     // Send file size first
-    socket
-        .send_to(&size_bytes, &target)
-        .expect("Failed to send file size");
+    link.send(&socket, &Message::Size(file_size), target)
+        .map_err(SndError::network("Failed to send file size"))?;
 
-    let mut sent_bytes: u64 = 0;
-    let mut sequence_number = 0u64;
-    let mut buffer = [0u8; CHUNK_SIZE + 8]; // Extra space for sequence number
+    let mut rtt = RttEstimator::new();
+    let mut window = CongestionWindow::new();
+    let mut in_flight: VecDeque<Unacked> = VecDeque::new();
+    // Index of the next packet in in_flight to send, everything before it is on its way
+    let mut next_send = 0;
+    let mut read_bytes: u64 = 0;
+    let mut acked: u64 = 0;
+    let mut next_seq = 0u64;
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let mut ack_buffer = [0u8; 64];
+    // Times the oldest packet has timed out in a row, and when the receiver was last heard from
+    let mut retries = 0;
+    let mut last_heard = Instant::now();
+
+    while read_bytes < file_size || !in_flight.is_empty() {
+        if link.cancelled() {
+            return Err(abort_stream(&socket, target, link));
+        }
+        // Send what the window allows, first anything going again after a loss then new data
+        while next_send < window.size() {
+            if next_send == in_flight.len() {
+                if read_bytes >= file_size {
+                    break;
+                }
+                let read_size = read_chunk(&mut file, &mut buffer)?;
+                if read_size == 0 {
+                    status_err!("{}", "The file ended before everything was sent".red());
+                    read_bytes = file_size;
+                    break;
+                }
+                let data = buffer[..read_size].to_vec();
+                in_flight.push_back(Unacked {
+                    seq: next_seq,
+                    packet: Message::Data {
+                        seq: next_seq,
+                        data,
+                    }
+                    .encode(link.version),
+                    sent_at: None,
+                    retransmitted: false,
+                });
+                read_bytes += read_size as u64;
+                next_seq += 1;
+            }
+            let unacked = &mut in_flight[next_send];
+            if unacked.sent_at.is_some() {
+                unacked.retransmitted = true;
+                progress.retransmit();
+            }
+            if let Err(e) = link.send_frame(&socket, &unacked.packet, target) {
+                status_err!("Failed to send chunk: {}", e);
+            }
+            unacked.sent_at = Some(Instant::now());
+            progress.packet();
+            next_send += 1;
+        }
+        progress.set_congestion(rtt.timeout(), window.size());
 
-    while sent_bytes < file_size {
-        // Reset timeout to initial value for each new packet
-        let mut current_timeout = INITIAL_TIMEOUT;
+        let Some(oldest) = in_flight.front().and_then(|u| u.sent_at) else {
+            continue;
+        };
+        // Wait for an ack until the oldest packet times out
+        let wait = rtt
+            .timeout()
+            .saturating_sub(oldest.elapsed())
+            .max(Duration::from_millis(1));
         socket
-            .set_read_timeout(Some(Duration::from_millis(current_timeout)))
-            .expect("Failed to set read timeout");
+            .set_read_timeout(Some(wait))
+            .map_err(SndError::network("Failed to set read timeout"))?;
+        match socket.recv_from(&mut ack_buffer) {
+            Ok((size, src)) if src == target => {
+                let reply = parse_reply(&ack_buffer[..size], link);
+                if reply.is_some() {
+                    last_heard = Instant::now();
+                }
+                match reply {
+                    Some(Message::Ack(seq)) => {
+                        while let Some(front) = in_flight.front()
+                            && front.seq <= seq
+                        {
+                            let unacked = in_flight.pop_front().unwrap();
+                            next_send = next_send.saturating_sub(1);
+                            // Acks are cumulative, only the packet the ack was sent for says
+                            // how long a round trip took
+                            if unacked.seq == seq
+                                && !unacked.retransmitted
+                                && let Some(sent_at) = unacked.sent_at
+                            {
+                                rtt.sample(sent_at.elapsed());
+                            }
+                            window.on_ack();
+                            let len = (unacked.packet.len() - DATA_OVERHEAD) as u64;
+                            progress.add(len);
+                            acked += len;
+                            retries = 0;
+                        }
+                    }
+                    // The chunk arrived damaged, which says nothing about congestion. Go back
+                    // to it straight away, the receiver drops everything after it
+                    Some(Message::Nack(seq)) => {
+                        if let Some(front) = in_flight.front()
+                            && seq >= front.seq
+                        {
+                            next_send = next_send.min((seq - front.seq) as usize);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => status_err!("Receive error: {}", e),
+        }
 
-        // Prepare chunk with sequence number
-        let seq_bytes = sequence_number.to_be_bytes();
-        buffer[0..8].copy_from_slice(&seq_bytes);
+        // The oldest packet timed out, take it as a loss and go back to it
+        if let Some(sent_at) = in_flight.front().and_then(|u| u.sent_at)
+            && sent_at.elapsed() >= rtt.timeout()
+        {
+            if retries >= limits.max_retries || last_heard.elapsed() >= limits.idle_timeout {
+                gave_up(retries, last_heard);
+                end_stream(&socket, target, link);
+                return Ok(false);
+            }
+            retries += 1;
+            rtt.backoff();
+            window.on_loss();
+            next_send = 0;
+        }
+    }
+    end_stream(&socket, target, link);
+    Ok(acked == file_size)
+}
 
-        file.seek(SeekFrom::Start(sent_bytes))
-            .expect("Failed to seek file");
+fn gave_up(retries: u64, last_heard: Instant) {
+    status_err!(
+        "{} {} {} {}",
+        "The receiver stopped responding, giving up after".red(),
+        retries,
+        "retries and".red(),
+        format!(
+            "{:.1}s without an answer",
+            last_heard.elapsed().as_secs_f64()
+        )
+        .red()
+    );
+}
 
-        let read_size = file
-            .read(&mut buffer[8..])
-            .expect("Failed to read file chunk");
+// Tells the receiver nothing more is coming on this stream so it doesn't wait for the rest. Only a
+// shortcut, if it gets lost the receiver times out as it would have anyway
+fn end_stream(socket: &UdpSocket, target: SocketAddr, link: Link) {
+    if let Err(e) = link.send(socket, &Message::Done, target) {
+        status_err!("Failed to send end of stream: {}", e);
+    }
+}
 
-        let chunk_end = 8 + read_size;
-        let packet = &buffer[..chunk_end];
+// Tells the receiver the sender cancelled so it stops waiting for the rest, the error to return
+fn abort_stream(socket: &UdpSocket, target: SocketAddr, link: Link) -> SndError {
+    if let Err(e) = link.send(socket, &Message::Abort, target) {
+        status_err!("Failed to send the cancellation: {}", e);
+    }
+    SndError::Cancelled
+}
 
-        let mut ack_received = false;
+// Opens an ACK or NACK
+fn parse_reply(reply: &[u8], link: Link) -> Option<Message> {
+    match link.open(reply)? {
+        reply @ (Message::Ack(_) | Message::Nack(_)) => Some(reply),
+        _ => None,
+    }
+}
+
+// Bytes left to send from the current position of the file
+fn remaining_size(file: &mut File) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    let pos = file.stream_position()?;
+    Ok(len.saturating_sub(pos))
+}
 
-        while !ack_received {
-            // Send the chunk
-            if let Err(e) = socket.send_to(packet, &target) {
-                eprintln!("Failed to send chunk: {}", e);
+// A packet that has been sent but not acknowledged yet
+struct InFlight {
+    packet: Vec<u8>,
+    sent_at: Instant,
+    timeout: u64,
+    retries: u64,
+}
+
+// Selective repeat: keeps up to WINDOW_SIZE sequence numbers past the oldest unacked packet in
+// flight and only retransmits the ones whose own timer ran out, the receiver acks every packet it
+// gets and buffers the out-of-order ones
+fn send_windowed(
+    mut file: impl Read,
+    file_size: u64,
+    target: SocketAddr,
+    link: Link,
+    progress: &Progress,
+) -> Result<bool, SndError> {
+    const CHUNK_SIZE: usize = 1392;
+    const INITIAL_TIMEOUT: u64 = 100;
+    const MAX_TIMEOUT: u64 = 2000;
+    const ACK_POLL: u64 = 5;
+
+    let limits = limits();
+    let socket =
+        UdpSocket::bind("0.0.0.0:0").map_err(SndError::network("Failed to bind to a port"))?;
+    socket
+        .set_read_timeout(Some(Duration::from_millis(ACK_POLL)))
+        .map_err(SndError::network("Failed to set read timeout"))?;
+
+    // Send file size first
+    link.send(&socket, &Message::Size(file_size), target)
+        .map_err(SndError::network("Failed to send file size"))?;
+
+    let mut in_flight: BTreeMap<u64, InFlight> = BTreeMap::new();
+    let mut read_bytes: u64 = 0;
+    let mut acked: u64 = 0;
+    let mut last_heard = Instant::now();
+    let mut next_seq = 0u64;
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let mut ack_buffer = [0u8; 64];
+
+    while read_bytes < file_size || !in_flight.is_empty() {
+        if link.cancelled() {
+            return Err(abort_stream(&socket, target, link));
+        }
+        // Fill the window with new packets. The window is a range of sequence numbers starting at
+        // the oldest unacked packet, the receiver drops anything past it without an ack
+        let base = in_flight.keys().next().copied().unwrap_or(next_seq);
+        while read_bytes < file_size && next_seq < base + WINDOW_SIZE {
+            let read_size = read_chunk(&mut file, &mut buffer)?;
+            if read_size == 0 {
+                // The file shrunk while sending, nothing more to read
+                read_bytes = file_size;
+                break;
+            }
+            let packet = Message::Data {
+                seq: next_seq,
+                data: buffer[..read_size].to_vec(),
             }
+            .encode(link.version);
 
-            // Wait for ACK
-            let mut ack_buffer = [0u8; 8];
-            match socket.recv_from(&mut ack_buffer) {
-                Ok((_, src)) => {
-                    if src == target {
-                        let received_seq = u64::from_be_bytes(ack_buffer);
-                        if received_seq == sequence_number {
-                            ack_received = true;
-                        }
+            if let Err(e) = link.send_frame(&socket, &packet, target) {
+                status_err!("Failed to send chunk: {}", e);
+            }
+            progress.packet();
+            in_flight.insert(
+                next_seq,
+                InFlight {
+                    packet,
+                    sent_at: Instant::now(),
+                    timeout: INITIAL_TIMEOUT,
+                    retries: 0,
+                },
+            );
+
+            read_bytes += read_size as u64;
+            next_seq += 1;
+        }
+
+        // Wait for ACKs
+        match socket.recv_from(&mut ack_buffer) {
+            Ok((size, src)) if src == target => {
+                let reply = parse_reply(&ack_buffer[..size], link);
+                if reply.is_some() {
+                    last_heard = Instant::now();
+                }
+                if let Some(Message::Ack(seq)) = reply {
+                    if let Some(inf) = in_flight.remove(&seq) {
+                        let len = (inf.packet.len() - DATA_OVERHEAD) as u64;
+                        progress.add(len);
+                        acked += len;
+                    }
+                } else if let Some(Message::Nack(seq)) = reply
+                    && let Some(inf) = in_flight.get_mut(&seq)
+                {
+                    // The chunk arrived damaged, resend it without waiting for its timer
+                    if let Err(e) = link.send_frame(&socket, &inf.packet, target) {
+                        status_err!("Failed to send chunk: {}", e);
+                    }
+                    progress.packet();
+                    inf.sent_at = Instant::now();
+                    progress.retransmit();
+                }
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => status_err!("Receive error: {}", e),
+        }
+
+        // Retransmit every packet whose own timer ran out
+        for inf in in_flight.values_mut() {
+            if inf.sent_at.elapsed() >= Duration::from_millis(inf.timeout) {
+                if inf.retries >= limits.max_retries || last_heard.elapsed() >= limits.idle_timeout
+                {
+                    gave_up(inf.retries, last_heard);
+                    end_stream(&socket, target, link);
+                    return Ok(false);
+                }
+                inf.retries += 1;
+                if let Err(e) = link.send_frame(&socket, &inf.packet, target) {
+                    status_err!("Failed to send chunk: {}", e);
+                }
+                progress.packet();
+                inf.sent_at = Instant::now();
+                inf.timeout = (inf.timeout * 2).min(MAX_TIMEOUT);
+                progress.retransmit();
+            }
+        }
+    }
+    end_stream(&socket, target, link);
+    Ok(acked == file_size)
+}
+
+// Announces the file at index of the offer with Start and waits on the control socket for the
+// receiver to answer with the ports it listens on, none for the UDP methods. A lost Start would
+// leave both sides waiting on each other, so it goes again until the receiver answers or the idle
+// timeout passes
+pub fn announce(
+    socket: &UdpSocket,
+    source: SocketAddr,
+    link: Link,
+    index: usize,
+) -> Result<Option<Vec<u16>>, SndError> {
+    let started = Instant::now();
+    let mut buf = [0; 1024];
+    while started.elapsed() < idle_timeout() {
+        if link.cancelled() {
+            return Err(SndError::Cancelled);
+        }
+        if let Err(e) = link.send(socket, &Message::Start { file: index }, source) {
+            status_err!("Failed to announce the file: {}", e);
+        }
+        socket
+            .set_read_timeout(Some(START_RESEND))
+            .map_err(SndError::network("Failed to set read timeout"))?;
+        let sent_at = Instant::now();
+        while sent_at.elapsed() < START_RESEND {
+            match socket.recv_from(&mut buf) {
+                Ok((size, src)) if src.ip() == source.ip() => {
+                    if let Some(Message::Ports(ports)) = link.open(&buf[..size]) {
+                        return Ok(Some(ports));
                     }
                 }
-                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if is_timeout(&e) => break,
                 Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock
-                        || e.kind() == std::io::ErrorKind::TimedOut
-                    {
-                        // Timeout occurred, will retry
-                    } else {
-                        eprintln!("Receive error: {}", e);
+                    status_err!("Receive error: {}", e);
+                    return Ok(None);
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Answers every Start waiting on the control socket with ports again, for when the first answer
+// got lost and the sender is still announcing the file. Anything else the sender sent is left on
+// the socket for whoever reads it next
+pub fn answer_starts(
+    socket: &UdpSocket,
+    source: SocketAddr,
+    link: Link,
+    ports: &[u16],
+) -> io::Result<()> {
+    socket.set_nonblocking(true)?;
+    let mut buf = [0; 1024];
+    let result = loop {
+        let (size, src) = match socket.peek_from(&mut buf) {
+            Ok(peeked) => peeked,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        if src.ip() == source.ip() {
+            if !matches!(link.open(&buf[..size]), Some(Message::Start { .. })) {
+                break Ok(());
+            }
+            if let Err(e) = link.send(socket, &Message::Ports(ports.to_vec()), src) {
+                status_err!("Failed to answer the announcement: {}", e);
+            }
+        }
+        // Take what was peeked off the socket, a Start that is answered or noise from elsewhere
+        if let Err(e) = socket.recv_from(&mut buf) {
+            break Err(e);
+        }
+    };
+    socket.set_nonblocking(false)?;
+    result
+}
+
+// Splits what is left of the file after offset into one byte range per target and sends each
+// over its own socket and thread with the windowed method. Every stream starts with the position
+// of its range and the total length of the file so the receiver knows where to write it
+pub fn send_file_parallel(
+    file: File,
+    offset: u64,
+    targets: &[SocketAddr],
+    link: Link,
+    watch: Watch,
+) -> Result<Stats, SndError> {
+    let len = file
+        .metadata()
+        .map_err(SndError::io("Failed to get metadata"))?
+        .len();
+    let remaining = len.saturating_sub(offset);
+    let per_stream = remaining.div_ceil(targets.len() as u64).max(1);
+    let progress = Progress::sending(remaining, watch);
+
+    let complete = thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .enumerate()
+            .map(|(i, &target)| {
+                let start = (offset + i as u64 * per_stream).min(len);
+                let end = (start + per_stream).min(len);
+                let file = &file;
+                let progress = &progress;
+                scope.spawn(move || {
+                    let mut header = [0u8; RANGE_HEADER_SIZE];
+                    header[..8].copy_from_slice(&start.to_be_bytes());
+                    header[8..].copy_from_slice(&len.to_be_bytes());
+                    let range = RangeReader {
+                        file,
+                        pos: start,
+                        end,
+                    };
+                    // The range headers count towards the progress as well
+                    progress.add_total(RANGE_HEADER_SIZE as u64);
+                    send_windowed(
+                        (&header[..]).chain(range),
+                        RANGE_HEADER_SIZE as u64 + end - start,
+                        target,
+                        link,
+                        progress,
+                    )
+                })
+            })
+            .collect();
+        // The scope still waits for the other streams once one has failed
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join().unwrap_or_else(|_| {
+                    Err(SndError::TransferFailed(
+                        "A sending thread panicked".to_string(),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<bool>, SndError>>()
+            .map(|streams| streams.into_iter().all(|done| done))
+    });
+
+    let stats = progress.finish(matches!(complete, Ok(true)));
+    complete?;
+    report_sent(&stats);
+    Ok(stats)
+}
+
+// Reads one byte range of a file shared between threads
+struct RangeReader<'a> {
+    file: &'a File,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = (self.end - self.pos).min(buf.len() as u64) as usize;
+        if want == 0 {
+            return Ok(0);
+        }
+        let read = self.file.read_at(&mut buf[..want], self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+// Receives a parallel transfer of a file_size byte file, one thread per socket. record is called
+// with every byte range that made it into the file. Returns how many bytes were still missing when
+// the streams stopped
+pub fn receive_file_parallel(
+    sockets: &[UdpSocket],
+    fp: &File,
+    file_size: u64,
+    sender: IpAddr,
+    link: Link,
+    progress: &Progress,
+    record: impl Fn(u64, u64) + Sync,
+) -> Result<u64, SndError> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = sockets
+            .iter()
+            .map(|socket| {
+                let record = &record;
+                scope.spawn(move || {
+                    let mut sink = RangeWriter {
+                        file: fp,
+                        len: file_size,
+                        header: Vec::with_capacity(RANGE_HEADER_SIZE),
+                        start: 0,
+                        pos: 0,
+                        record,
+                    };
+                    receive_file_udp(
+                        socket,
+                        &mut sink,
+                        None,
+                        "windowed",
+                        sender,
+                        link,
+                        progress,
+                        |_| {},
+                    )
+                })
+            })
+            .collect();
+        let missing = handles
+            .into_iter()
+            .map(|h| {
+                h.join().unwrap_or_else(|_| {
+                    Err(SndError::TransferFailed(
+                        "A receiving thread panicked".to_string(),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<u64>, SndError>>()?;
+        Ok(missing.into_iter().fold(0, u64::saturating_add))
+    })
+}
+
+// Writes one stream of a parallel transfer into its place in the file, the stream's first bytes
+// say where that is
+struct RangeWriter<'a, F: Fn(u64, u64)> {
+    file: &'a File,
+    // Length of the file as offered, no stream may claim another or write past it
+    len: u64,
+    header: Vec<u8>,
+    start: u64,
+    pos: u64,
+    record: &'a F,
+}
+
+impl<F: Fn(u64, u64)> Write for RangeWriter<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = buf;
+        if self.header.len() < RANGE_HEADER_SIZE {
+            let take = (RANGE_HEADER_SIZE - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.header.len() == RANGE_HEADER_SIZE {
+                self.start = u64::from_be_bytes(self.header[..8].try_into().unwrap());
+                self.pos = self.start;
+                let len = u64::from_be_bytes(self.header[8..].try_into().unwrap());
+                if len != self.len || self.start > len {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Stream range doesn't fit the offered file",
+                    ));
+                }
+                // Every stream knows the total length, growing the file to it up front means
+                // the ranges can be written in any order
+                if self.file.metadata()?.len() < len {
+                    self.file.set_len(len)?;
+                }
+            }
+        }
+        if self.pos.saturating_add(data.len() as u64) > self.len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Stream runs past the end of the offered file",
+            ));
+        }
+        if !data.is_empty() {
+            self.file.write_all_at(data, self.pos)?;
+            self.pos += data.len() as u64;
+            (self.record)(self.start, self.pos);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn send_file_tcp(
+    mut file: impl Read,
+    file_size: u64,
+    target: SocketAddr,
+    link: Link,
+    progress: &Progress,
+) -> Result<bool, SndError> {
+    let limits = limits();
+    let mut stream = match TcpStream::connect_timeout(&target, limits.idle_timeout) {
+        Ok(stream) => stream,
+        Err(e) => {
+            status_err!("{}: {}", "Failed to connect to receiver".red(), e);
+            return Ok(false);
+        }
+    };
+    // Writes block once the receiver stops reading, don't wait on it forever
+    for timeout in [
+        stream.set_write_timeout(Some(limits.idle_timeout)),
+        stream.set_read_timeout(Some(limits.idle_timeout)),
+    ] {
+        timeout.map_err(SndError::network("Failed to set stream timeout"))?;
+    }
+
+    // Send file size first, after that the stream carries nothing but the data
+    let size = Message::Size(file_size).encode(link.version);
+    if let Err(e) = write_message(&mut stream, &size, link) {
+        status_err!("{}: {}", "Failed to send file size".red(), e);
+        return Ok(false);
+    }
+
+    let mut buf = vec![0u8; TCP_CHUNK];
+    let mut sent = 0;
+    loop {
+        // Dropping the stream cuts the receiver short, the Abort on the control socket tells it why
+        if link.cancelled() {
+            return Err(SndError::Cancelled);
+        }
+        let bytes_read = read_chunk(&mut file, &mut buf)?;
+        if bytes_read == 0 {
+            break;
+        }
+        if let Err(e) = write_message(&mut stream, &buf[..bytes_read], link) {
+            let reason = if is_timeout(&e) {
+                "The receiver stopped reading".to_string()
+            } else {
+                e.to_string()
+            };
+            status_err!("{}: {}", "Failed to send data chunk".red(), reason);
+            return Ok(false);
+        }
+        progress.packet();
+        progress.add(bytes_read as u64);
+        sent += bytes_read as u64;
+    }
+    // The receiver closes the connection once it has everything, waiting for that tells a
+    // finished transfer apart from one the receiver walked away from
+    let closed = stream
+        .flush()
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .and_then(|_| stream.read(&mut [0u8; 1]));
+    Ok(match closed {
+        Ok(0) => sent == file_size,
+        Ok(_) => false,
+        Err(e) if is_timeout(&e) => {
+            status_err!("{}", "The receiver never confirmed it got everything".red());
+            false
+        }
+        Err(e) => {
+            status_err!(
+                "{}: {}",
+                "The receiver never confirmed the transfer".red(),
+                e
+            );
+            false
+        }
+    })
+}
+
+// Receives a file over TCP, calling on_write with the number of bytes written so far. expected is
+// the size the offer said, see check_size. Returns how many bytes were still missing when the
+// connection closed
+pub fn receive_file_tcp(
+    listener: &TcpListener,
+    fp: &mut impl Write,
+    expected: Option<u64>,
+    sender: IpAddr,
+    link: Link,
+    progress: &Progress,
+    mut on_write: impl FnMut(u64),
+) -> Result<u64, SndError> {
+    let limits = limits();
+    let Some(mut stream) = accept_from(listener, sender, limits.idle_timeout)? else {
+        return Ok(NOTHING_RECEIVED);
+    };
+    stream
+        .set_read_timeout(Some(limits.idle_timeout))
+        .map_err(SndError::network("Failed to set read timeout"))?;
+
+    let size = match link.session {
+        Some(session) => read_sealed(&mut stream, session),
+        None => read_frame(&mut stream),
+    };
+    let file_size = match size.map(|frame| Message::decode(&frame)) {
+        Ok(Some(Message::Size(size))) => check_size(size, expected)?,
+        Ok(_) => {
+            status_err!("{}", "The sender didn't start with the file size".red());
+            return Ok(NOTHING_RECEIVED);
+        }
+        Err(e) => {
+            status_err!("{}: {}", "Failed to receive file size".red(), e);
+            return Ok(NOTHING_RECEIVED);
+        }
+    };
+    progress.add_total(file_size);
+
+    let mut remaining = file_size;
+    let mut buf = vec![0u8; TCP_CHUNK];
+    while remaining > 0 {
+        let want = remaining.min(buf.len() as u64) as usize;
+        let result = match link.session {
+            Some(session) => read_sealed(&mut stream, session).map(Cow::Owned),
+            None => stream
+                .read(&mut buf[..want])
+                .map(|n| Cow::Borrowed(&buf[..n])),
+        };
+        let data = match result {
+            Ok(data) if data.is_empty() => break,
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) if is_timeout(&e) => {
+                went_quiet(limits.idle_timeout);
+                break;
+            }
+            Err(e) => {
+                status_err!("Receive error: {}", e);
+                break;
+            }
+        };
+        progress.packet();
+        let count = data.len().min(remaining as usize);
+        fp.write_all(&data[..count])
+            .map_err(SndError::io("Failed to write chunk"))?;
+        remaining -= count as u64;
+        progress.add(count as u64);
+        on_write(file_size - remaining);
+    }
+
+    if remaining > 0 {
+        status_err!(
+            "Connection closed early: received {} of {} bytes",
+            file_size - remaining,
+            file_size
+        );
+    }
+    Ok(remaining)
+}
+
+// Waits up to timeout for the sender to connect, connections from anyone else are turned away
+fn accept_from(
+    listener: &TcpListener,
+    sender: IpAddr,
+    timeout: Duration,
+) -> Result<Option<TcpStream>, SndError> {
+    listener
+        .set_nonblocking(true)
+        .map_err(SndError::network("Failed to set non-blocking"))?;
+    let started = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) if peer.ip() == sender => {
+                stream
+                    .set_nonblocking(false)
+                    .map_err(SndError::network("Failed to set blocking"))?;
+                return Ok(Some(stream));
+            }
+            Ok((_, peer)) => status_err!("Ignoring TCP connection from unexpected peer {}", peer),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if started.elapsed() >= timeout {
+                    went_quiet(timeout);
+                    return Ok(None);
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                status_err!("{}: {}", "Failed to accept connection".red(), e);
+                return Ok(None);
+            }
+        }
+    }
+}
+
+fn went_quiet(timeout: Duration) {
+    status_err!(
+        "{}",
+        format!(
+            "Nothing from the sender for {}s, giving up",
+            timeout.as_secs()
+        )
+        .red()
+    );
+}
+
+// Writes a message to a TCP stream. Sealed messages are prefixed with their length since the
+// stream itself has no message boundaries
+fn write_message(stream: &mut TcpStream, data: &[u8], link: Link) -> io::Result<()> {
+    match link.session {
+        Some(session) => {
+            let sealed = session.seal(data);
+            stream.write_all(&(sealed.len() as u32).to_be_bytes())?;
+            stream.write_all(&sealed)
+        }
+        None => stream.write_all(data),
+    }
+}
+
+fn read_sealed(stream: &mut TcpStream, session: &Session) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf)?;
+    // The length isn't authenticated yet, don't let it decide how much memory is set aside
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > TCP_CHUNK + SEAL_OVERHEAD {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Sealed chunk is larger than any sender writes",
+        ));
+    }
+    let mut sealed = vec![0u8; len];
+    stream.read_exact(&mut sealed)?;
+    session
+        .open(&sealed)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Failed to decrypt chunk"))
+}
+
+// Receives a file sent with one of the UDP send methods, calling on_write with the number of bytes
+// written so far. expected is the size the offer said, see check_size. Returns how many bytes were
+// still missing when the transfer stopped
+#[allow(clippy::too_many_arguments)]
+pub fn receive_file_udp(
+    socket: &UdpSocket,
+    fp: &mut impl Write,
+    expected: Option<u64>,
+    send_method: &str,
+    sender: IpAddr,
+    link: Link,
+    progress: &Progress,
+    mut on_write: impl FnMut(u64),
+) -> Result<u64, SndError> {
+    let limits = limits();
+    socket
+        .set_read_timeout(Some(limits.idle_timeout))
+        .map_err(SndError::network("Failed to set read timeout"))?;
+    let mut size_buf = [0u8; 64];
+    let (sender, file_size) = loop {
+        let (size, src) = match socket.recv_from(&mut size_buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if is_timeout(&e) => {
+                went_quiet(limits.idle_timeout);
+                return Ok(NOTHING_RECEIVED);
+            }
+            Err(e) => {
+                status_err!("{}: {}", "Failed to receive file size".red(), e);
+                return Ok(NOTHING_RECEIVED);
+            }
+        };
+        if src.ip() != sender {
+            continue;
+        }
+        match link.open(&size_buf[..size]) {
+            Some(Message::Size(size)) => break (src, check_size(size, expected)?),
+            // The answer to Start got lost and the sender is still announcing the file
+            Some(Message::Start { .. }) => {
+                if let Err(e) = link.send(socket, &Message::Ports(Vec::new()), src) {
+                    status_err!("Failed to answer the announcement: {}", e);
+                }
+            }
+            _ => {}
+        }
+    };
+    progress.add_total(file_size);
+    let mut remaining = file_size;
+    let mut chunk_buf = [0u8; 1500];
+
+    let sequenced = send_method == "semi-reliable" || send_method == "windowed";
+    let windowed = send_method == "windowed";
+    let mut next_expected_seq = 0;
+    // Packets that arrived ahead of next_expected_seq in windowed mode
+    let mut pending: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+
+    while remaining > 0 {
+        let (count, src) = match socket.recv_from(&mut chunk_buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if is_timeout(&e) => {
+                went_quiet(limits.idle_timeout);
+                break;
+            }
+            Err(e) => {
+                status_err!("Receive error: {}", e);
+                break;
+            }
+        };
+
+        // Stray packets from anyone else on the port
+        if src != sender {
+            continue;
+        }
+        progress.packet();
+        let (seq_num, data) = match link.open(&chunk_buf[..count]) {
+            Some(Message::Data { seq, data }) => (seq, data),
+            Some(Message::Damaged { seq }) if sequenced => {
+                status_err!("Corrupt packet {}, asking for it again", seq);
+                send_reply(socket, Message::Nack(seq), src, link);
+                continue;
+            }
+            Some(Message::Damaged { seq }) => {
+                status_err!("Corrupt packet {}, skipping", seq);
+                continue;
+            }
+            // The sender stopped, whatever is still missing isn't coming
+            Some(Message::Done) => break,
+            Some(Message::Abort) => {
+                status_err!("{}", "The sender cancelled the transfer".red());
+                break;
+            }
+            _ => {
+                status_err!("Packet failed to decode, skipping");
+                continue;
+            }
+        };
+
+        if sequenced {
+            // Skip duplicate packets
+            if seq_num < next_expected_seq {
+                // Still ACK duplicates to prevent retries
+                send_reply(socket, Message::Ack(seq_num), src, link);
+                progress.duplicate();
+                continue;
+            }
+
+            if seq_num != next_expected_seq {
+                // Buffer packets that are ahead but still inside the sender's window
+                if windowed && seq_num < next_expected_seq + WINDOW_SIZE {
+                    if pending.insert(seq_num, data).is_some() {
+                        progress.duplicate();
                     }
+                    send_reply(socket, Message::Ack(seq_num), src, link);
+                } else {
+                    // Skip out-of-order packets, the sender resends them once they time out
+                    progress.out_of_order();
                 }
+                continue;
             }
+        }
+
+        let before = remaining;
+        write_chunk(fp, &data, &mut remaining)?;
 
-            // Handle retry
-            if !ack_received {
-                current_timeout = (current_timeout * 2).min(MAX_TIMEOUT);
-                socket
-                    .set_read_timeout(Some(Duration::from_millis(current_timeout)))
-                    .expect("Failed to set read timeout");
+        if sequenced {
+            next_expected_seq += 1;
+
+            // Send ACK with sequence number
+            send_reply(socket, Message::Ack(seq_num), src, link);
+        }
+
+        // Flush whatever the new packet made contiguous
+        while let Some(buffered) = pending.remove(&next_expected_seq) {
+            write_chunk(fp, &buffered, &mut remaining)?;
+            next_expected_seq += 1;
+        }
+
+        progress.add(before - remaining);
+        on_write(file_size - remaining);
+    }
+    if sequenced && remaining == 0 {
+        linger(socket, sender, link);
+    }
+    Ok(remaining)
+}
+
+// The size a stream starts with has to be what the offer said, the receiver's limits were checked
+// against the offer and nothing past it is written. None for the streams of a parallel transfer,
+// their RangeWriter holds them to the offer instead
+fn check_size(size: u64, expected: Option<u64>) -> Result<u64, SndError> {
+    match expected {
+        Some(expected) if size != expected => Err(SndError::TransferFailed(format!(
+            "The sender announced {} bytes where the offer said {}",
+            size, expected
+        ))),
+        _ => Ok(size),
+    }
+}
+
+// Keeps acking retransmissions after the last packet in case the sender never heard some of the
+// acks, until the sender says it is done or goes quiet. Anything else arriving (usually the next
+// Start) is left on the socket for whoever reads it next
+fn linger(socket: &UdpSocket, sender: SocketAddr, link: Link) {
+    if socket.set_read_timeout(Some(LINGER)).is_err() {
+        return;
+    }
+    let mut buf = [0u8; 1500];
+    loop {
+        match socket.peek_from(&mut buf) {
+            Ok((_, src)) if src == sender => {
+                let Ok((count, _)) = socket.recv_from(&mut buf) else {
+                    return;
+                };
+                match link.open(&buf[..count]) {
+                    Some(Message::Data { seq, .. }) => {
+                        send_reply(socket, Message::Ack(seq), sender, link)
+                    }
+                    Some(Message::Done) => return,
+                    _ => {}
+                }
             }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            _ => return,
         }
+    }
+}
 
-        sent_bytes += read_size as u64;
-        sequence_number += 1;
+fn send_reply(socket: &UdpSocket, reply: Message, src: SocketAddr, link: Link) {
+    if let Err(e) = link.send(socket, &reply, src) {
+        status_err!("Failed to send ACK: {}", e);
     }
+}
+
+fn write_chunk(fp: &mut impl Write, data: &[u8], remaining: &mut u64) -> Result<(), SndError> {
+    if !data.is_empty() {
+        let write_size = std::cmp::min(*remaining, data.len() as u64) as usize;
+        fp.write_all(&data[..write_size])
+            .map_err(SndError::io("Failed to write chunk"))?;
+        *remaining -= write_size as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK: Link = Link {
+        version: VERSION,
+        session: None,
+        cancel: None,
+    };
 
-    println!("{}", "File transfer complete!".green());
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    fn millis(duration: Duration) -> f64 {
+        duration.as_secs_f64() * 1000.0
+    }
+
+    #[test]
+    fn timeout_follows_the_measured_round_trip() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(millis(rtt.timeout()), RttEstimator::INITIAL);
+
+        // The first sample sets the variance to half of it
+        rtt.sample(Duration::from_millis(40));
+        assert!((millis(rtt.timeout()) - 120.0).abs() < 1e-6);
+
+        // A steady link settles just above its round trip
+        for _ in 0..200 {
+            rtt.sample(Duration::from_millis(10));
+        }
+        assert!((millis(rtt.timeout()) - 11.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn timeout_stays_within_bounds() {
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::ZERO);
+        assert_eq!(millis(rtt.timeout()), RttEstimator::MIN);
+
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_secs(10));
+        assert_eq!(millis(rtt.timeout()), RttEstimator::MAX);
+    }
+
+    #[test]
+    fn backoff_doubles_until_the_next_sample() {
+        let mut rtt = RttEstimator::new();
+        rtt.backoff();
+        assert_eq!(millis(rtt.timeout()), 2.0 * RttEstimator::INITIAL);
+        for _ in 0..20 {
+            rtt.backoff();
+        }
+        assert_eq!(millis(rtt.timeout()), RttEstimator::MAX);
+
+        rtt.sample(Duration::from_millis(40));
+        assert!((millis(rtt.timeout()) - 120.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn window_grows_by_a_packet_per_ack_in_slow_start() {
+        let mut window = CongestionWindow::new();
+        assert_eq!(window.size(), CongestionWindow::INITIAL as usize);
+        for _ in 0..4 {
+            window.on_ack();
+        }
+        assert_eq!(window.size(), 8);
+
+        // Never past the window the receiver keeps
+        for _ in 0..WINDOW_SIZE * 4 {
+            window.on_ack();
+        }
+        assert_eq!(window.size(), WINDOW_SIZE as usize);
+    }
+
+    #[test]
+    fn window_halves_on_loss_then_grows_a_packet_per_round_trip() {
+        let mut window = CongestionWindow::new();
+        for _ in 0..12 {
+            window.on_ack();
+        }
+        assert_eq!(window.size(), 16);
+        window.on_loss();
+        assert_eq!(window.size(), 8);
+
+        // A window's worth of acks adds about one packet
+        for _ in 0..8 {
+            window.on_ack();
+        }
+        assert_eq!(window.size(), 8);
+        window.on_ack();
+        assert_eq!(window.size(), 9);
+    }
+
+    #[test]
+    fn window_never_closes() {
+        let mut window = CongestionWindow::new();
+        for _ in 0..10 {
+            window.on_loss();
+        }
+        assert_eq!(window.size(), CongestionWindow::MIN as usize);
+        window.on_ack();
+        assert_eq!(window.size(), 2);
+    }
+
+    #[test]
+    fn announced_sizes_must_match_the_offer() {
+        assert_eq!(check_size(10, Some(10)).unwrap(), 10);
+        assert!(check_size(11, Some(10)).is_err());
+        // Parallel streams carry their own ranges, checked as they are written
+        assert_eq!(check_size(11, None).unwrap(), 11);
+    }
+
+    #[test]
+    fn answers_repeated_starts_and_leaves_the_rest() {
+        let (control, sender) = (socket(), socket());
+        let to = control.local_addr().unwrap();
+        let start = Message::Start { file: 0 }.encode(VERSION);
+        sender.send_to(&start, to).unwrap();
+        sender.send_to(&start, to).unwrap();
+        sender.send_to(&Message::Abort.encode(VERSION), to).unwrap();
+        let source = sender.local_addr().unwrap();
+        answer_starts(&control, source, LINK, &[4000, 4001]).unwrap();
+
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            let (size, _) = sender.recv_from(&mut buf).unwrap();
+            assert!(matches!(
+                Message::decode(&buf[..size]),
+                Some(Message::Ports(ports)) if ports == [4000, 4001]
+            ));
+        }
+        let (size, _) = control.recv_from(&mut buf).unwrap();
+        assert!(matches!(
+            Message::decode(&buf[..size]),
+            Some(Message::Abort)
+        ));
+    }
 }
@@ -0,0 +1,371 @@
+use crate::utils::human_readable_size;
+use colored::Colorize;
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+// Off when used as a library, the CLI turns it on. Status messages, the progress bar and
+// summaries only go to the terminal when it is
+static CONSOLE: AtomicBool = AtomicBool::new(false);
+// Set by --quiet, scripts don't want a progress bar redrawn on their output
+static QUIET: AtomicBool = AtomicBool::new(false);
+// Set by --json, transfer summaries are printed as one JSON object per line instead and
+// everything else goes to stderr so stdout stays parseable
+static JSON: AtomicBool = AtomicBool::new(false);
+
+const BAR_WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn set_console(console: bool) {
+    CONSOLE.store(console, Ordering::Relaxed);
+}
+
+pub fn console() -> bool {
+    CONSOLE.load(Ordering::Relaxed)
+}
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Where a transfer is at, handed to progress callbacks
+#[derive(Clone, Copy, Debug)]
+pub struct Snapshot {
+    /// Bytes moved so far and how many there are in total
+    pub done: u64,
+    pub total: u64,
+    /// Current rate in bytes per second
+    pub rate: f64,
+    /// Packets sent again (sending) or received twice (receiving)
+    pub retransmits: u64,
+    pub duplicates: u64,
+}
+
+// Called with every update of a transfer, at most every REDRAW_INTERVAL and once more at the end
+pub type Watch<'a> = &'a (dyn Fn(&Snapshot) + Sync);
+
+// Progress of one transfer, shared between every thread working on it. Draws a bar with the bytes
+// done, current rate, retransmits (or duplicates when receiving) and ETA on a terminal, and keeps
+// the counters the summary at the end is made of
+pub struct Progress<'a> {
+    sending: bool,
+    total: AtomicU64,
+    done: AtomicU64,
+    packets: AtomicU64,
+    retransmits: AtomicU64,
+    duplicates: AtomicU64,
+    out_of_order: AtomicU64,
+    // Retransmission timeout (microseconds) and congestion window of senders that adapt them, 0
+    // for the rest
+    timeout: AtomicU64,
+    window: AtomicU64,
+    started: Instant,
+    rate: Mutex<RateState>,
+    // Only redraw in place on a terminal, a log file gets the summary alone
+    live: bool,
+    watch: Watch<'a>,
+}
+
+struct RateState {
+    at: Instant,
+    done: u64,
+    rate: f64,
+    peak: f64,
+}
+
+impl<'a> Progress<'a> {
+    pub fn sending(total: u64, watch: Watch<'a>) -> Progress<'a> {
+        Progress::new(true, total, watch)
+    }
+
+    // The receiver learns the size from the first packet, it is added with add_total
+    pub fn receiving(watch: Watch<'a>) -> Progress<'a> {
+        Progress::new(false, 0, watch)
+    }
+
+    fn new(sending: bool, total: u64, watch: Watch<'a>) -> Progress<'a> {
+        let now = Instant::now();
+        Progress {
+            sending,
+            total: AtomicU64::new(total),
+            done: AtomicU64::new(0),
+            packets: AtomicU64::new(0),
+            retransmits: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            out_of_order: AtomicU64::new(0),
+            timeout: AtomicU64::new(0),
+            window: AtomicU64::new(0),
+            started: now,
+            rate: Mutex::new(RateState {
+                at: now,
+                done: 0,
+                rate: 0.0,
+                peak: 0.0,
+            }),
+            live: console()
+                && !QUIET.load(Ordering::Relaxed)
+                && !JSON.load(Ordering::Relaxed)
+                && io::stdout().is_terminal(),
+            watch,
+        }
+    }
+
+    // For transfers made of several streams whose sizes are only learnt as they start
+    pub fn add_total(&self, bytes: u64) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.done.fetch_add(bytes, Ordering::Relaxed);
+        self.tick();
+    }
+
+    pub fn packet(&self) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn retransmit(&self) {
+        self.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn out_of_order(&self) {
+        self.out_of_order.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_congestion(&self, timeout: Duration, window: usize) {
+        self.timeout
+            .store(timeout.as_micros() as u64, Ordering::Relaxed);
+        self.window.store(window as u64, Ordering::Relaxed);
+    }
+
+    // Updates the rate every REDRAW_INTERVAL, redrawing the bar with it when there is one
+    fn tick(&self) {
+        let Ok(mut state) = self.rate.try_lock() else {
+            // Another thread is updating it right now
+            return;
+        };
+        let elapsed = state.at.elapsed();
+        if elapsed < REDRAW_INTERVAL {
+            return;
+        }
+        let done = self.done.load(Ordering::Relaxed);
+        let instant = done.saturating_sub(state.done) as f64 / elapsed.as_secs_f64();
+        // Smooth the rate so the ETA doesn't jump around with every burst
+        state.rate = if state.rate == 0.0 {
+            instant
+        } else {
+            state.rate * 0.7 + instant * 0.3
+        };
+        state.peak = state.peak.max(state.rate);
+        state.at = Instant::now();
+        state.done = done;
+        (self.watch)(&self.snapshot(done, state.rate));
+        if self.live {
+            self.draw(done, state.rate);
+        }
+    }
+
+    fn snapshot(&self, done: u64, rate: f64) -> Snapshot {
+        Snapshot {
+            done,
+            total: self.total.load(Ordering::Relaxed).max(done),
+            rate,
+            retransmits: self.retransmits.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+        }
+    }
+
+    fn draw(&self, done: u64, rate: f64) {
+        let total = self.total.load(Ordering::Relaxed).max(done);
+        let fraction = if total == 0 {
+            1.0
+        } else {
+            done as f64 / total as f64
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        // No ETA before anything has moved, or while it is so slow the ETA doesn't fit a Duration
+        let eta = Duration::try_from_secs_f64((total - done) as f64 / rate)
+            .map(format_duration)
+            .unwrap_or_else(|_| "--:--".to_string());
+        let (label, count) = if self.sending {
+            ("retransmits", &self.retransmits)
+        } else {
+            ("duplicates", &self.duplicates)
+        };
+        print!(
+            "\r\x1b[K[{}{}] {:>3.0}% {} / {}  {}/s  ETA {}  {}: {}",
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+            human_readable_size(done),
+            human_readable_size(total),
+            human_readable_size(rate as u64),
+            eta,
+            label,
+            count.load(Ordering::Relaxed)
+        );
+        let window = self.window.load(Ordering::Relaxed);
+        if window > 0 {
+            print!(
+                "  timeout {:.1}ms  window {}",
+                self.timeout.load(Ordering::Relaxed) as f64 / 1000.0,
+                window
+            );
+        }
+        let _ = io::stdout().flush();
+    }
+
+    // Clears the bar and returns what the transfer came to, complete is whether everything made it
+    pub fn finish(self, complete: bool) -> Stats {
+        if self.live {
+            print!("\r\x1b[K");
+            let _ = io::stdout().flush();
+        }
+        let duration = self.started.elapsed();
+        let bytes = self.done.load(Ordering::Relaxed);
+        let average = bytes as f64 / duration.as_secs_f64().max(0.001);
+        (self.watch)(&self.snapshot(bytes, average));
+        let peak = self.rate.into_inner().map_or(0.0, |state| state.peak);
+        Stats {
+            sending: self.sending,
+            complete,
+            total: self.total.into_inner(),
+            bytes,
+            duration,
+            average,
+            // Transfers shorter than one update never get a peak of their own
+            peak: peak.max(average),
+            packets: self.packets.into_inner(),
+            retransmits: self.retransmits.into_inner(),
+            duplicates: self.duplicates.into_inner(),
+            out_of_order: self.out_of_order.into_inner(),
+        }
+    }
+}
+
+/// Summary of a finished transfer
+pub struct Stats {
+    sending: bool,
+    pub complete: bool,
+    // Bytes the transfer was meant to move and how many it did
+    pub total: u64,
+    pub bytes: u64,
+    pub duration: Duration,
+    // Bytes per second
+    pub average: f64,
+    pub peak: f64,
+    pub packets: u64,
+    pub retransmits: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+}
+
+impl Stats {
+    // Prints the summary of the transfer of path, as JSON with --json. digest is the SHA-256 of
+    // the data when it is known
+    pub fn report(&self, path: &str, digest: Option<&str>) {
+        if !console() {
+            return;
+        }
+        if JSON.load(Ordering::Relaxed) {
+            println!("{}", self.to_json(path, digest));
+            return;
+        }
+        if QUIET.load(Ordering::Relaxed) {
+            return;
+        }
+        let amount = if self.complete {
+            human_readable_size(self.bytes)
+        } else {
+            format!(
+                "{} of {}, incomplete",
+                human_readable_size(self.bytes),
+                human_readable_size(self.total)
+            )
+        };
+        println!(
+            "{} {} ({}) in {}",
+            if self.sending { "Sent" } else { "Received" }.green(),
+            path.blue(),
+            amount,
+            format_duration(self.duration)
+        );
+        println!(
+            "  {}/s average, {}/s peak",
+            human_readable_size(self.average as u64),
+            human_readable_size(self.peak as u64)
+        );
+        println!(
+            "  {} packets, {} retransmits, {} duplicates, {} out of order",
+            self.packets, self.retransmits, self.duplicates, self.out_of_order
+        );
+        if let Some(digest) = digest {
+            println!("  sha256 {}", digest);
+        }
+    }
+
+    fn to_json(&self, path: &str, digest: Option<&str>) -> String {
+        format!(
+            "{{\"direction\":\"{}\",\"path\":{},\"complete\":{},\"total_bytes\":{},\"bytes\":{},\"duration_secs\":{:.3},\"average_bytes_per_sec\":{:.0},\"peak_bytes_per_sec\":{:.0},\"packets\":{},\"retransmits\":{},\"duplicates\":{},\"out_of_order\":{},\"sha256\":{}}}",
+            if self.sending { "sent" } else { "received" },
+            json_string(path),
+            self.complete,
+            self.total,
+            self.bytes,
+            self.duration.as_secs_f64(),
+            self.average,
+            self.peak,
+            self.packets,
+            self.retransmits,
+            self.duplicates,
+            self.out_of_order,
+            digest.map_or("null".to_string(), json_string)
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
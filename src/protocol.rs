@@ -0,0 +1,724 @@
+use crate::{crypto::Session, sender::Cancel, types::OfferedFile};
+use std::{
+    io::{self, ErrorKind, Read},
+    net::{SocketAddr, UdpSocket},
+};
+
+// Wire format versions this build speaks. Receivers announce their range in Hello, senders offer
+// in the highest version both sides know and everything after that uses the version of the offer
+pub const VERSION: u8 = 1;
+pub const MIN_VERSION: u8 = 1;
+
+// Every message is a frame: the magic, the version it is encoded in, its kind and the length of
+// the body that follows
+const MAGIC: &[u8; 3] = b"SND";
+pub const FRAME_HEADER_SIZE: usize = 9;
+
+// Bytes a Data frame adds to the chunk it carries, the sequence number and a CRC32
+pub const DATA_OVERHEAD: usize = FRAME_HEADER_SIZE + 12;
+
+const HELLO: u8 = 1;
+const OFFER: u8 = 2;
+const PAKE: u8 = 3;
+const ACCEPT: u8 = 4;
+const REJECT: u8 = 5;
+const START: u8 = 6;
+const PORTS: u8 = 7;
+const SIZE: u8 = 8;
+const DATA: u8 = 9;
+const ACK: u8 = 10;
+const NACK: u8 = 11;
+const DONE: u8 = 12;
+const WITHDRAW: u8 = 13;
+const ABORT: u8 = 14;
+
+#[derive(Debug)]
+pub enum Message {
+    // Broadcast by receivers so senders can find them. Its layout never changes so any version
+    // can read it and pick a version both sides speak
+    Hello {
+        name: String,
+        min_version: u8,
+        max_version: u8,
+    },
+    Offer {
+        offer: Offer,
+        signature: Option<Signature>,
+    },
+    // Each side's half of the pairing exchange. The sender answers with the real offer frame,
+    // sealed with the key the exchange produced
+    Pake {
+        message: Vec<u8>,
+        sealed_offer: Vec<u8>,
+    },
+    // The files the receiver wants, by index into the offer, and how much of each it already has
+    Accept {
+        from: String,
+        files: Vec<(usize, u64)>,
+    },
+    Reject {
+        from: String,
+        reason: String,
+    },
+    // The sender is about to send the file at this index of the offer, sent again until the
+    // receiver answers with Ports
+    Start {
+        file: usize,
+    },
+    // Where the receiver listens for the data, one port for TCP, one per parallel stream and none
+    // when the data comes to the socket that answered Start
+    Ports(Vec<u16>),
+    // First message of every data stream, how many bytes follow
+    Size(u64),
+    Data {
+        seq: u64,
+        data: Vec<u8>,
+    },
+    // Never sent, what a Data frame whose CRC doesn't match decodes to so it can be asked for
+    // again
+    Damaged {
+        seq: u64,
+    },
+    Ack(u64),
+    Nack(u64),
+    // The sender has nothing more to send on this stream, whether or not it all arrived
+    Done,
+    // The sender took back the offer with this id before it was answered
+    Withdraw {
+        id: u32,
+    },
+    // The sender cancelled the transfer, sent on the data stream and on the control socket
+    Abort,
+}
+
+// What a sender offers. Encrypted offers only carry the name until the receiver has paired
+#[derive(Debug)]
+pub struct Offer {
+    pub name: String,
+    // Picked at random by the sender, the sealed and unsealed copies of an offer share it
+    pub id: u32,
+    // Seconds the sender waits for an answer before giving up on the offer
+    pub expires_in: u32,
+    pub sealed: bool,
+    pub send_method: String,
+    // How many streams a parallel transfer is split into
+    pub streams: u16,
+    pub files: Vec<OfferedFile>,
+}
+
+// The sender's public key and its signature over the offer
+#[derive(Debug)]
+pub struct Signature {
+    pub key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl Offer {
+    // The bytes the signature covers, the offer's own fields without the signature
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_str(&mut body, &self.name);
+        body.extend_from_slice(&self.id.to_be_bytes());
+        body.extend_from_slice(&self.expires_in.to_be_bytes());
+        body.push(self.sealed as u8);
+        put_str(&mut body, &self.send_method);
+        body.extend_from_slice(&self.streams.to_be_bytes());
+        body.extend_from_slice(&(self.files.len() as u32).to_be_bytes());
+        for file in &self.files {
+            put_str(&mut body, &file.path);
+            put_str(&mut body, &file.file_type);
+            body.extend_from_slice(&file.size.to_be_bytes());
+            match &file.digest {
+                Some(digest) => {
+                    body.push(1);
+                    put_str(&mut body, digest);
+                }
+                None => body.push(0),
+            }
+            body.push(file.streamed as u8);
+        }
+        body
+    }
+
+    fn read(reader: &mut Reader) -> Option<Offer> {
+        let name = reader.string()?;
+        let id = reader.u32()?;
+        let expires_in = reader.u32()?;
+        let sealed = reader.u8()? != 0;
+        let send_method = reader.string()?;
+        let streams = reader.u16()?;
+        let count = reader.u32()?;
+        // Not preallocated, the count is only as trustworthy as whoever sent it
+        let mut files = Vec::new();
+        for _ in 0..count {
+            let file = OfferedFile {
+                path: reader.string()?,
+                file_type: reader.string()?,
+                size: reader.u64()?,
+                digest: match reader.u8()? {
+                    0 => None,
+                    _ => Some(reader.string()?),
+                },
+                streamed: reader.u8()? != 0,
+            };
+            // Every sender since version 1 streams directories, a directory sent any other way
+            // would be saved without the checks unpacking does
+            if file.file_type == "directory" && !file.streamed {
+                return None;
+            }
+            files.push(file);
+        }
+        Some(Offer {
+            name,
+            id,
+            expires_in,
+            sealed,
+            send_method,
+            streams,
+            files,
+        })
+    }
+}
+
+impl Message {
+    pub fn encode(&self, version: u8) -> Vec<u8> {
+        let mut body = Vec::new();
+        let kind = match self {
+            Message::Hello {
+                name,
+                min_version,
+                max_version,
+            } => {
+                put_str(&mut body, name);
+                body.push(*min_version);
+                body.push(*max_version);
+                HELLO
+            }
+            Message::Offer { offer, signature } => {
+                put_bytes(&mut body, &offer.signed_bytes());
+                if let Some(signature) = signature {
+                    body.extend_from_slice(&signature.key);
+                    body.extend_from_slice(&signature.signature);
+                }
+                OFFER
+            }
+            Message::Pake {
+                message,
+                sealed_offer,
+            } => {
+                put_bytes(&mut body, message);
+                put_bytes(&mut body, sealed_offer);
+                PAKE
+            }
+            Message::Accept { from, files } => {
+                put_str(&mut body, from);
+                body.extend_from_slice(&(files.len() as u32).to_be_bytes());
+                for &(file, resume) in files {
+                    body.extend_from_slice(&(file as u32).to_be_bytes());
+                    body.extend_from_slice(&resume.to_be_bytes());
+                }
+                ACCEPT
+            }
+            Message::Reject { from, reason } => {
+                put_str(&mut body, from);
+                put_str(&mut body, reason);
+                REJECT
+            }
+            Message::Start { file } => {
+                body.extend_from_slice(&(*file as u32).to_be_bytes());
+                START
+            }
+            Message::Ports(ports) => {
+                body.extend_from_slice(&(ports.len() as u16).to_be_bytes());
+                for port in ports {
+                    body.extend_from_slice(&port.to_be_bytes());
+                }
+                PORTS
+            }
+            Message::Size(size) => {
+                body.extend_from_slice(&size.to_be_bytes());
+                SIZE
+            }
+            Message::Data { seq, data } => {
+                body.extend_from_slice(&seq.to_be_bytes());
+                body.extend_from_slice(&data_crc(*seq, data).to_be_bytes());
+                body.extend_from_slice(data);
+                DATA
+            }
+            Message::Damaged { .. } => unreachable!("Damaged is never sent"),
+            Message::Ack(seq) => {
+                body.extend_from_slice(&seq.to_be_bytes());
+                ACK
+            }
+            Message::Nack(seq) => {
+                body.extend_from_slice(&seq.to_be_bytes());
+                NACK
+            }
+            Message::Done => DONE,
+            Message::Withdraw { id } => {
+                body.extend_from_slice(&id.to_be_bytes());
+                WITHDRAW
+            }
+            Message::Abort => ABORT,
+        };
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + body.len());
+        frame.extend_from_slice(MAGIC);
+        frame.push(version);
+        frame.push(kind);
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    // Decodes a frame in any version this build speaks. None for anything else: not snd at all,
+    // a version we don't know, truncated, or a kind added in a later version
+    pub fn decode(frame: &[u8]) -> Option<Message> {
+        let (version, kind, body) = split_frame(frame)?;
+        // Hello is read whatever the version, it is how versions are agreed on in the first place
+        if kind != HELLO && !(MIN_VERSION..=VERSION).contains(&version) {
+            return None;
+        }
+        let mut reader = Reader { buf: body };
+        let message = match kind {
+            HELLO => Message::Hello {
+                name: reader.string()?,
+                min_version: reader.u8()?,
+                max_version: reader.u8()?,
+            },
+            OFFER => {
+                let offer = Offer::read(&mut Reader {
+                    buf: reader.bytes()?,
+                })?;
+                // Unsigned offers end after the offer itself
+                let signature = match reader.buf.len() {
+                    0 => None,
+                    96 => Some(Signature {
+                        key: reader.take(32)?.try_into().ok()?,
+                        signature: reader.take(64)?.try_into().ok()?,
+                    }),
+                    _ => return None,
+                };
+                Message::Offer { offer, signature }
+            }
+            PAKE => Message::Pake {
+                message: reader.bytes()?.to_vec(),
+                sealed_offer: reader.bytes()?.to_vec(),
+            },
+            ACCEPT => {
+                let from = reader.string()?;
+                let count = reader.u32()?;
+                let mut files = Vec::new();
+                for _ in 0..count {
+                    files.push((reader.u32()? as usize, reader.u64()?));
+                }
+                Message::Accept { from, files }
+            }
+            REJECT => Message::Reject {
+                from: reader.string()?,
+                reason: reader.string()?,
+            },
+            START => Message::Start {
+                file: reader.u32()? as usize,
+            },
+            PORTS => {
+                let count = reader.u16()?;
+                let mut ports = Vec::new();
+                for _ in 0..count {
+                    ports.push(reader.u16()?);
+                }
+                Message::Ports(ports)
+            }
+            SIZE => Message::Size(reader.u64()?),
+            DATA => {
+                let seq = reader.u64()?;
+                let crc = reader.u32()?;
+                let data = reader.buf;
+                if data_crc(seq, data) != crc {
+                    return Some(Message::Damaged { seq });
+                }
+                Message::Data {
+                    seq,
+                    data: data.to_vec(),
+                }
+            }
+            ACK => Message::Ack(reader.u64()?),
+            NACK => Message::Nack(reader.u64()?),
+            DONE => Message::Done,
+            WITHDRAW => Message::Withdraw { id: reader.u32()? },
+            ABORT => Message::Abort,
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+// The version a frame is encoded in, even one this build can't decode
+pub fn frame_version(frame: &[u8]) -> Option<u8> {
+    split_frame(frame).map(|(version, _, _)| version)
+}
+
+// Whether a frame is an offer, whatever its version
+pub fn is_offer(frame: &[u8]) -> bool {
+    split_frame(frame).is_some_and(|(_, kind, _)| kind == OFFER)
+}
+
+// Highest version both sides speak, given the range the other side announced
+pub fn negotiate(min_version: u8, max_version: u8) -> Option<u8> {
+    let version = max_version.min(VERSION);
+    (version >= min_version.max(MIN_VERSION)).then_some(version)
+}
+
+fn split_frame(frame: &[u8]) -> Option<(u8, u8, &[u8])> {
+    if frame.len() < FRAME_HEADER_SIZE || &frame[..3] != MAGIC {
+        return None;
+    }
+    let len = u32::from_be_bytes(frame[5..9].try_into().unwrap()) as usize;
+    let body = frame.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE.checked_add(len)?)?;
+    Some((frame[3], frame[4], body))
+}
+
+// Reads one frame off a TCP stream, which has no message boundaries of its own
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut frame = vec![0u8; FRAME_HEADER_SIZE];
+    stream.read_exact(&mut frame)?;
+    if &frame[..3] != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Not an snd frame"));
+    }
+    let len = u32::from_be_bytes(frame[5..9].try_into().unwrap()) as usize;
+    // Only ever used for small control messages, anything bigger isn't one
+    if len > 1024 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Frame too large"));
+    }
+    frame.resize(FRAME_HEADER_SIZE + len, 0);
+    stream.read_exact(&mut frame[FRAME_HEADER_SIZE..])?;
+    Ok(frame)
+}
+
+// The CRC covers the sequence number and the data
+fn data_crc(seq: u64, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&seq.to_be_bytes());
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn put_bytes(body: &mut Vec<u8>, bytes: &[u8]) {
+    body.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    body.extend_from_slice(bytes);
+}
+
+fn put_str(body: &mut Vec<u8>, s: &str) {
+    put_bytes(body, s.as_bytes());
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+}
+
+// The version a transfer was agreed on and its encryption, if any. Everything sent during a
+// transfer goes through it, so it also carries the sender's way to cancel
+#[derive(Clone, Copy)]
+pub struct Link<'a> {
+    pub version: u8,
+    pub session: Option<&'a Session>,
+    pub cancel: Option<&'a Cancel>,
+}
+
+impl Link<'_> {
+    // Whether the sender asked to stop, checked by every send loop
+    pub fn cancelled(&self) -> bool {
+        self.cancel.is_some_and(Cancel::is_cancelled)
+    }
+
+    // Encodes a message and sends it, sealed when the transfer is encrypted
+    pub fn send(
+        &self,
+        socket: &UdpSocket,
+        message: &Message,
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        self.send_frame(socket, &message.encode(self.version), target)
+    }
+
+    // Sends a frame that was encoded earlier, such as a packet that is sent again
+    pub fn send_frame(
+        &self,
+        socket: &UdpSocket,
+        frame: &[u8],
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        match self.session {
+            Some(session) => socket.send_to(&session.seal(frame), target),
+            None => socket.send_to(frame, target),
+        }
+    }
+
+    // Opens and decodes a received datagram, None if it doesn't belong to the transfer
+    pub fn open(&self, datagram: &[u8]) -> Option<Message> {
+        match self.session {
+            Some(session) => Message::decode(&session.open(datagram)?),
+            None => Message::decode(datagram),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn offer() -> Offer {
+        Offer {
+            name: "alpha".to_string(),
+            id: 0xdead_beef,
+            expires_in: 30,
+            sealed: false,
+            send_method: "windowed".to_string(),
+            streams: 4,
+            files: vec![
+                OfferedFile {
+                    path: "/tmp/a.bin".to_string(),
+                    file_type: "Binary file".to_string(),
+                    size: 3_000_000,
+                    digest: Some("0f74".to_string()),
+                    streamed: false,
+                },
+                OfferedFile {
+                    path: "/tmp/dir".to_string(),
+                    file_type: "directory".to_string(),
+                    size: u64::MAX,
+                    digest: None,
+                    streamed: true,
+                },
+            ],
+        }
+    }
+
+    // One of every kind of message that goes on the wire
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Hello {
+                name: "bravo".to_string(),
+                min_version: MIN_VERSION,
+                max_version: VERSION,
+            },
+            Message::Offer {
+                offer: offer(),
+                signature: None,
+            },
+            Message::Offer {
+                offer: offer(),
+                signature: Some(Signature {
+                    key: [7; 32],
+                    signature: [9; 64],
+                }),
+            },
+            Message::Pake {
+                message: vec![1, 2, 3],
+                sealed_offer: vec![4; 100],
+            },
+            Message::Accept {
+                from: "bravo".to_string(),
+                files: vec![(0, 0), (1, 1 << 40)],
+            },
+            Message::Reject {
+                from: "bravo".to_string(),
+                reason: "not today".to_string(),
+            },
+            Message::Start { file: 1 },
+            Message::Ports(vec![]),
+            Message::Ports(vec![4000, 4001, 65535]),
+            Message::Size(u64::MAX),
+            Message::Data {
+                seq: 42,
+                data: vec![0xab; 1392],
+            },
+            Message::Data {
+                seq: 0,
+                data: vec![],
+            },
+            Message::Ack(7),
+            Message::Nack(8),
+            Message::Done,
+            Message::Withdraw { id: 0xdead_beef },
+            Message::Abort,
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in messages() {
+            let frame = message.encode(VERSION);
+            let decoded = Message::decode(&frame).expect("frame should decode");
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+            assert_eq!(decoded.encode(VERSION), frame);
+        }
+    }
+
+    #[test]
+    fn damaged_data_is_told_apart() {
+        let mut frame = Message::Data {
+            seq: 5,
+            data: vec![1; 10],
+        }
+        .encode(VERSION);
+        *frame.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            Message::decode(&frame),
+            Some(Message::Damaged { seq: 5 })
+        ));
+    }
+
+    #[test]
+    fn truncated_frames_are_refused() {
+        for message in messages() {
+            let frame = message.encode(VERSION);
+            for len in 0..frame.len() {
+                assert!(
+                    Message::decode(&frame[..len]).is_none(),
+                    "{:?} cut to {} bytes decoded",
+                    message,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bodies_shorter_than_their_fields_are_refused() {
+        // A whole frame whose body ends before the message does
+        let mut frame = Message::Size(1).encode(VERSION);
+        frame.truncate(FRAME_HEADER_SIZE + 4);
+        frame[5..9].copy_from_slice(&4u32.to_be_bytes());
+        assert!(Message::decode(&frame).is_none());
+
+        // A string claiming more bytes than there are
+        let mut body = Vec::new();
+        body.extend_from_slice(&u32::MAX.to_be_bytes());
+        body.extend_from_slice(b"bravo");
+        let mut frame = MAGIC.to_vec();
+        frame.extend_from_slice(&[VERSION, REJECT]);
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&body);
+        assert!(Message::decode(&frame).is_none());
+    }
+
+    #[test]
+    fn oversize_lengths_are_refused() {
+        let mut frame = Message::Ack(1).encode(VERSION);
+        frame[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Message::decode(&frame).is_none());
+        assert!(frame_version(&frame).is_none());
+
+        let mut stream = Cursor::new(frame);
+        let err = read_frame(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn directories_that_arent_streamed_are_refused() {
+        let mut offer = offer();
+        offer.files[1].streamed = false;
+        let frame = Message::Offer {
+            offer,
+            signature: None,
+        }
+        .encode(VERSION);
+        assert!(Message::decode(&frame).is_none());
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        for version in [MIN_VERSION - 1, VERSION + 1, u8::MAX] {
+            let frame = Message::Start { file: 0 }.encode(version);
+            assert!(Message::decode(&frame).is_none());
+            assert_eq!(frame_version(&frame), Some(version));
+
+            // Offers are still recognised so the sender can be told which versions work
+            let frame = Message::Offer {
+                offer: offer(),
+                signature: None,
+            }
+            .encode(version);
+            assert!(Message::decode(&frame).is_none());
+            assert!(is_offer(&frame));
+
+            // and Hello is read whatever its version
+            let frame = Message::Hello {
+                name: "bravo".to_string(),
+                min_version: version,
+                max_version: version,
+            }
+            .encode(version);
+            assert!(Message::decode(&frame).is_some());
+        }
+    }
+
+    #[test]
+    fn other_traffic_is_refused() {
+        assert!(Message::decode(b"").is_none());
+        assert!(Message::decode(b"hello;alpha;1").is_none());
+        let mut frame = Message::Done.encode(VERSION);
+        frame[..3].copy_from_slice(b"SNE");
+        assert!(Message::decode(&frame).is_none());
+        // A kind this version doesn't know
+        let mut frame = Message::Done.encode(VERSION);
+        frame[4] = u8::MAX;
+        assert!(Message::decode(&frame).is_none());
+    }
+
+    #[test]
+    fn frames_are_read_off_a_stream_one_at_a_time() {
+        let mut bytes = Message::Size(1234).encode(VERSION);
+        bytes.extend(Message::Done.encode(VERSION));
+        let mut stream = Cursor::new(bytes);
+        let first = read_frame(&mut stream).unwrap();
+        assert!(matches!(Message::decode(&first), Some(Message::Size(1234))));
+        let second = read_frame(&mut stream).unwrap();
+        assert!(matches!(Message::decode(&second), Some(Message::Done)));
+        assert!(read_frame(&mut stream).is_err());
+    }
+
+    #[test]
+    fn versions_are_negotiated_within_both_ranges() {
+        assert_eq!(negotiate(MIN_VERSION, VERSION), Some(VERSION));
+        assert_eq!(negotiate(MIN_VERSION, VERSION + 5), Some(VERSION));
+        assert_eq!(negotiate(VERSION + 1, VERSION + 5), None);
+    }
+}
@@ -0,0 +1,1003 @@
+use crate::{
+    archive::{Skipped, UnpackSink},
+    crypto::{Pake, Role, Session},
+    error::SndError,
+    identity::{is_trusted, verify_offer},
+    journal::{Journal, corrupt_path, partial_path},
+    network::{
+        MAX_DATAGRAM, PORT, answer_starts, idle_timeout, receive_file_parallel, receive_file_tcp,
+        receive_file_udp, spawn_broadcast_thread,
+    },
+    progress::{Progress, Snapshot, Watch},
+    protocol::{Link, MIN_VERSION, Message, VERSION, frame_version, is_offer},
+    types::{Collision, DM, HostInfo, OfferedFile},
+    utils::{
+        default_download_dir, downloadfc, file_digest, gen_cname, human_readable_size,
+        numbered_path, read_config,
+    },
+};
+use colored::Colorize;
+use std::{
+    fs::{create_dir_all, remove_dir_all, remove_file, rename},
+    io::{self, Write},
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+// Most streams a receiver opens for a parallel transfer, whatever the offer asks for
+const MAX_STREAMS: usize = 64;
+// How often the control socket is checked for a repeated Start while the data isn't coming yet
+const START_POLL: Duration = Duration::from_millis(50);
+
+/// What a sender can tell a receiver outside of a transfer
+pub enum Incoming {
+    Offer(DM),
+    /// The sender at `ip` took back the offer with this [`DM::id`]
+    Withdrawn {
+        ip: IpAddr,
+        id: u32,
+    },
+}
+
+/// Listens for offers on the local network, announcing itself so senders can find it
+pub struct Receiver {
+    socket: UdpSocket,
+}
+
+impl Receiver {
+    /// Takes the snd port and starts announcing this machine every couple of seconds
+    pub fn bind() -> Result<Receiver, SndError> {
+        let socket = UdpSocket::bind(("0.0.0.0", PORT))
+            .map_err(SndError::network("Failed to bind to port"))?;
+        socket
+            .set_broadcast(true)
+            .map_err(SndError::network("Failed to set broadcast"))?;
+        // Broadcast from a clone, the original socket is used for receiving
+        spawn_broadcast_thread(
+            socket
+                .try_clone()
+                .map_err(SndError::network("Failed to clone socket"))?,
+        );
+        Ok(Receiver { socket })
+    }
+
+    /// Blocks until the next offer arrives, anything else sent to the port is skipped
+    pub fn next_offer(&self) -> Result<DM, SndError> {
+        loop {
+            if let Incoming::Offer(dm) = self.next_incoming()? {
+                return Ok(dm);
+            }
+        }
+    }
+
+    /// Blocks until the next offer arrives or a sender withdraws one it made earlier
+    pub fn next_incoming(&self) -> Result<Incoming, SndError> {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, source)) => {
+                    let frame = &buf[..size];
+                    if let Some(dm) = parse_offer(frame, source.ip()) {
+                        return Ok(Incoming::Offer(dm));
+                    }
+                    if let Some(Message::Withdraw { id }) = Message::decode(frame) {
+                        return Ok(Incoming::Withdrawn {
+                            ip: source.ip(),
+                            id,
+                        });
+                    }
+                    if is_offer(frame) {
+                        self.refuse_version(frame, source);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(SndError::Network("Error receiving message".to_string(), e)),
+            }
+        }
+    }
+
+    /// Turns an offer down so its sender stops waiting, telling it why if there is a `reason`
+    pub fn reject(&self, dm: &DM, reason: Option<&str>) -> Result<(), SndError> {
+        let target = SocketAddr::new(dm.host_info.ip, PORT);
+        self.send_reject(target, dm.host_info.version, reason.unwrap_or(""))
+            .map_err(SndError::network("Failed to send the rejection"))
+    }
+
+    // Turns down an offer in a version this build can't read, so the sender doesn't wait for an
+    // answer that never comes
+    fn refuse_version(&self, frame: &[u8], source: SocketAddr) {
+        let version = frame_version(frame).unwrap_or(0);
+        status_err!(
+            "Ignoring an offer from {} in protocol version {}",
+            source.ip(),
+            version
+        );
+        let reason = format!(
+            "this receiver only speaks protocol versions {} to {}",
+            MIN_VERSION, VERSION
+        );
+        let target = SocketAddr::new(source.ip(), PORT);
+        if let Err(e) = self.send_reject(target, version.clamp(MIN_VERSION, VERSION), &reason) {
+            status_err!("Failed to turn down the offer: {}", e);
+        }
+    }
+
+    // Offers always come from the sender's snd port, and that is where it waits for the answer
+    fn send_reject(&self, target: SocketAddr, version: u8, reason: &str) -> io::Result<()> {
+        let reject = Message::Reject {
+            from: gen_cname(),
+            reason: reason.to_string(),
+        };
+        self.socket.send_to(&reject.encode(version), target)?;
+        Ok(())
+    }
+
+    /// Accepts an offer and receives the files picked by `choose` (indexes into `dm.files`).
+    /// They are saved to `dest` if given: a folder if it ends in a slash, already is one or more
+    /// than one file is chosen, otherwise the new path of the one file. Without it they go to
+    /// the folder the config has for the sender, see [`default_download_dir`]. When something
+    /// is already there `on_collision` is asked what to do about it. Encrypted offers
+    /// need the `pairing_code` shown on the sender, their files are only known once paired so
+    /// `choose` is handed the unsealed offer. `on_progress` is called with the position of the
+    /// file among the chosen ones and how far along it is.
+    /// Directories are unpacked without trusting the archive: entries that would end up outside
+    /// the folder, special files and anything past the advertised size are left out and listed.
+    /// Fails unless every chosen file arrived and checked out, straight away if the offer
+    /// has expired and with [`SndError::Usage`] if `choose` picks a file twice or one the offer
+    /// doesn't have
+    pub fn accept(
+        &self,
+        dm: &DM,
+        pairing_code: Option<&str>,
+        dest: Option<&Path>,
+        choose: impl FnOnce(&DM) -> Vec<usize>,
+        on_collision: impl FnMut(&Path) -> Collision,
+        on_progress: impl Fn(usize, &Snapshot) + Sync,
+    ) -> Result<(), SndError> {
+        if dm.is_expired() {
+            return Err(SndError::TransferFailed(
+                "The sender stopped waiting for an answer to this offer".to_string(),
+            ));
+        }
+        let socket = UdpSocket::bind(("0.0.0.0", 0))
+            .map_err(SndError::network("Failed to bind a socket"))?;
+        let target = SocketAddr::new(dm.host_info.ip, PORT);
+
+        // Encrypted offers have to be paired first, which is also what reveals the files
+        let (session, unsealed) = if dm.sealed {
+            let Some(code) = pairing_code else {
+                return Err(SndError::TransferFailed(
+                    "The offer is encrypted, accepting it needs the pairing code".to_string(),
+                ));
+            };
+            match pair_with_sender(&socket, target, code, dm.host_info.version)? {
+                Some((session, unsealed)) => (Some(session), Some(unsealed)),
+                None => {
+                    return Err(SndError::TransferFailed(
+                        "Pairing with the sender failed".to_string(),
+                    ));
+                }
+            }
+        } else {
+            (None, None)
+        };
+        let dm = unsealed.as_ref().unwrap_or(dm);
+        if !dm.identity.as_deref().is_some_and(is_trusted) {
+            status!(
+                "{}",
+                "Warning: this offer is not from a trusted device, anyone could have sent it"
+                    .yellow()
+            );
+        }
+
+        let chosen = choose(dm);
+        for (n, &i) in chosen.iter().enumerate() {
+            if i >= dm.files.len() {
+                return Err(SndError::Usage(format!(
+                    "File {} was chosen but the offer only has {}",
+                    i,
+                    dm.files.len()
+                )));
+            }
+            if chosen[..n].contains(&i) {
+                return Err(SndError::Usage(format!("File {} was chosen twice", i)));
+            }
+        }
+        // Only the last part of the path the sender gave is used, which has to be a plain name
+        let chosen: Vec<usize> = chosen
+            .into_iter()
+            .filter(|&i| {
+                let named = Path::new(&dm.files[i].path).file_name().is_some();
+                if !named {
+                    status_err!(
+                        "{} {}",
+                        "Skipping a file without a usable name:".yellow(),
+                        dm.files[i].path
+                    );
+                }
+                named
+            })
+            .collect();
+        if chosen.is_empty() {
+            status!("{}", "No files selected".yellow());
+            return Ok(());
+        }
+
+        // Pick up earlier, interrupted downloads of the same files if there are any. Streamed
+        // directories are unpacked as they arrive so there is nothing to resume them from. Working
+        // out where they go also makes sure there is somewhere to save to before accepting
+        let placed = chosen.iter().copied().zip(destinations(dm, &chosen, dest)?);
+        let placed = settle_collisions(placed, on_collision);
+        if placed.is_empty() {
+            status!("{}", "Every selected file was skipped".yellow());
+            return Ok(());
+        }
+        let journals = placed
+            .into_iter()
+            .map(|(i, dest)| {
+                let file = &dm.files[i];
+                let journal = (!file.streamed)
+                    .then(|| Journal::load(&dest, &file.path, file.size, file.digest.as_deref()));
+                (i, dest, journal)
+            })
+            .collect::<Vec<(usize, PathBuf, Option<Journal>)>>();
+        let resume_offset =
+            |journal: &Option<Journal>| journal.as_ref().map_or(0, Journal::resume_offset);
+        for (i, _, journal) in &journals {
+            let offset = resume_offset(journal);
+            if offset > 0 {
+                status!(
+                    "{} {} {} {}",
+                    "Resuming earlier transfer of".yellow(),
+                    dm.files[*i].path.blue(),
+                    "from".yellow(),
+                    human_readable_size(offset).blue()
+                );
+            }
+        }
+
+        // Every chosen file with where to resume it
+        let accept = Message::Accept {
+            from: gen_cname(),
+            files: journals
+                .iter()
+                .map(|(i, _, journal)| (*i, resume_offset(journal)))
+                .collect(),
+        };
+        // Everything from here on is in the version of the offer
+        let link = Link {
+            version: dm.host_info.version,
+            session: session.as_ref(),
+            cancel: None,
+        };
+        if let Err(e) = link.send(&socket, &accept, target) {
+            status_err!("Failed to send acceptance: {}", e);
+        } else {
+            let what = match chosen.as_slice() {
+                [i] => dm.files[*i].path.clone(),
+                _ => format!("{} files", chosen.len()),
+            };
+            status!(
+                "{} {} {} {} {} {}",
+                "Request sent to".green(),
+                dm.host_info.name.blue().bold(),
+                "to send".green(),
+                what.blue().bold(),
+                "using mode".green(),
+                dm.send_method.blue().bold(),
+            );
+        }
+
+        let mut failed = 0;
+        for (n, (i, dest, journal)) in journals.into_iter().enumerate() {
+            // The sender may still be confirming before the first file, but no longer than the
+            // offer is open for. Later files follow right away
+            let mut timeout = idle_timeout();
+            if n == 0 {
+                timeout += dm
+                    .expires
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+            }
+            let source = await_start(&socket, dm.host_info.ip, link, i, timeout)?;
+            let watch = |snapshot: &Snapshot| on_progress(n, snapshot);
+            let received = match journal {
+                Some(journal) => {
+                    receive_file(&socket, source, dm, &dm.files[i], journal, link, &watch)
+                }
+                None => receive_directory(
+                    &socket,
+                    source,
+                    &dm.send_method,
+                    &dm.files[i],
+                    &dest,
+                    link,
+                    &watch,
+                ),
+            }?;
+            if !received {
+                if sender_cancelled(&socket, dm.host_info.ip, link) {
+                    return Err(cancelled());
+                }
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(SndError::TransferFailed(format!(
+                "{} of {} files did not arrive intact",
+                failed,
+                chosen.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Decodes an offer frame. Encrypted offers only carry the hostname, the rest is sent sealed once
+// the receiver has typed in the pairing code
+fn parse_offer(frame: &[u8], ip: IpAddr) -> Option<DM> {
+    let version = frame_version(frame)?;
+    let Message::Offer { offer, signature } = Message::decode(frame)? else {
+        return None;
+    };
+    let identity = verify_offer(&offer, signature.as_ref());
+    let received = SystemTime::now();
+    Some(DM {
+        id: offer.id,
+        received,
+        expires: received + Duration::from_secs(offer.expires_in as u64),
+        host_info: HostInfo {
+            name: offer.name,
+            ip,
+            version,
+        },
+        send_method: offer.send_method,
+        streams: (offer.streams as usize).clamp(1, MAX_STREAMS),
+        files: offer.files,
+        sealed: offer.sealed,
+        identity,
+    })
+}
+
+// Receiver side of pairing: runs the exchange with the sender using the code shown there and
+// unseals the offer. Returns None if the code was wrong or the sender didn't answer
+fn pair_with_sender(
+    socket: &UdpSocket,
+    target: SocketAddr,
+    code: &str,
+    version: u8,
+) -> Result<Option<(Session, DM)>, SndError> {
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .map_err(SndError::network("Failed to set read timeout"))?;
+    let paired = exchange_pairing_code(socket, target, code, version);
+    socket
+        .set_read_timeout(None)
+        .map_err(SndError::network("Failed to set read timeout"))?;
+    Ok(paired)
+}
+
+fn exchange_pairing_code(
+    socket: &UdpSocket,
+    target: SocketAddr,
+    code: &str,
+    version: u8,
+) -> Option<(Session, DM)> {
+    let pake = Pake::start(Role::Receiver, code);
+    let request = Message::Pake {
+        message: pake.message().to_vec(),
+        sealed_offer: Vec::new(),
+    };
+    if let Err(e) = socket.send_to(&request.encode(version), target) {
+        status_err!("Failed to send pairing request: {}", e);
+        return None;
+    }
+
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let reply = loop {
+        match socket.recv_from(&mut buf) {
+            Ok((size, src)) if src == target => {
+                break Message::decode(&buf[..size]);
+            }
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                status_err!(
+                    "{}: {}",
+                    "The sender did not answer the pairing request".red(),
+                    e
+                );
+                return None;
+            }
+        }
+    };
+
+    let Some(Message::Pake {
+        message: peer,
+        sealed_offer,
+    }) = reply
+    else {
+        return None;
+    };
+    let session = pake.finish(&peer)?;
+    let Some(offer) = session.open(&sealed_offer) else {
+        status!("{}", "Wrong pairing code".red());
+        return None;
+    };
+    let dm = parse_offer(&offer, target.ip())?;
+    status!("{}: {}", "Unlocked offer".green(), dm);
+    Some((session, dm))
+}
+
+// Waits for the sender to announce the file at `index` of the offer, returning where it came from
+fn await_start(
+    socket: &UdpSocket,
+    sender_ip: IpAddr,
+    link: Link,
+    index: usize,
+    timeout: Duration,
+) -> Result<SocketAddr, SndError> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 1400];
+    loop {
+        // Packets from anyone else don't count as hearing from the sender
+        let left = deadline.saturating_duration_since(Instant::now());
+        socket
+            .set_read_timeout(Some(left.max(Duration::from_millis(1))))
+            .map_err(SndError::network("Failed to set read timeout"))?;
+        match socket.recv_from(&mut buf) {
+            Ok((size, source)) => {
+                if source.ip() != sender_ip {
+                    continue;
+                }
+                match link.open(&buf[..size]) {
+                    Some(Message::Start { file }) if file == index => {
+                        socket
+                            .set_read_timeout(None)
+                            .map_err(SndError::network("Failed to set read timeout"))?;
+                        return Ok(source);
+                    }
+                    Some(Message::Abort) => return Err(cancelled()),
+                    _ => {}
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                return Err(SndError::Network(
+                    "The sender stopped sending".to_string(),
+                    e,
+                ));
+            }
+        }
+    }
+}
+
+// Where each chosen file is saved, see Receiver::accept. Folders are created if they don't exist
+fn destinations(dm: &DM, chosen: &[usize], dest: Option<&Path>) -> Result<Vec<PathBuf>, SndError> {
+    let name = |i: usize| {
+        Path::new(&dm.files[i].path)
+            .file_name()
+            .unwrap_or_default()
+            .to_os_string()
+    };
+    let Some(dest) = dest else {
+        let dir = default_download_dir(dm, &read_config())?;
+        return Ok(chosen.iter().map(|&i| dir.join(name(i))).collect());
+    };
+    let is_dir =
+        dest.as_os_str().as_encoded_bytes().ends_with(b"/") || dest.is_dir() || chosen.len() > 1;
+    let dir = match dest.parent() {
+        Some(parent) if !is_dir => parent,
+        _ => dest,
+    };
+    create_dir_all(dir).map_err(SndError::io(format!("Failed to create {}", dir.display())))?;
+    Ok(match chosen {
+        [_] if !is_dir => vec![dest.to_path_buf()],
+        _ => chosen.iter().map(|&i| dest.join(name(i))).collect(),
+    })
+}
+
+// Settles what happens to files that would land on something already there, skipped files are
+// dropped. Renamed files also keep clear of the names earlier files of the offer were given
+fn settle_collisions(
+    placed: impl Iterator<Item = (usize, PathBuf)>,
+    mut on_collision: impl FnMut(&Path) -> Collision,
+) -> Vec<(usize, PathBuf)> {
+    let mut settled: Vec<(usize, PathBuf)> = Vec::new();
+    for (i, dest) in placed {
+        // Anything already there counts, broken symlinks included
+        let taken =
+            |path: &Path| settled.iter().any(|(_, p)| p == path) || path.symlink_metadata().is_ok();
+        let dest = if !taken(&dest) {
+            dest
+        } else {
+            match on_collision(&dest) {
+                Collision::Overwrite => dest,
+                Collision::Skip => {
+                    status!(
+                        "{} {}",
+                        "Skipping, already exists:".yellow(),
+                        dest.display()
+                    );
+                    continue;
+                }
+                Collision::Rename => {
+                    let renamed = (1..)
+                        .map(|n| numbered_path(&dest, n))
+                        .find(|p| !taken(p))
+                        .unwrap_or(dest);
+                    status!(
+                        "{} {}",
+                        "Already exists, saving as".yellow(),
+                        renamed.display()
+                    );
+                    renamed
+                }
+            }
+        };
+        settled.push((i, dest));
+    }
+    settled
+}
+
+// Clears whatever is in the way of a received file or directory, only ever something the
+// collision policy said to overwrite or a leftover partial directory
+fn make_room(path: &Path) -> Result<(), SndError> {
+    let removed = match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => remove_dir_all(path),
+        Ok(_) => remove_file(path),
+        Err(_) => return Ok(()),
+    };
+    removed.map_err(SndError::io(format!(
+        "Failed to replace {}",
+        path.display()
+    )))
+}
+
+// Lists what unpacking a received directory left out, so nothing goes missing unnoticed
+fn report_skipped(dir: &Path, skipped: &[Skipped]) {
+    if skipped.is_empty() {
+        return;
+    }
+    status_err!(
+        "{} {}",
+        format!("Left {} entries out of", skipped.len())
+            .yellow()
+            .bold(),
+        dir.display()
+    );
+    for entry in skipped {
+        status_err!("  {}: {}", entry.path, entry.reason);
+    }
+}
+
+// A file that stopped short may be the sender cancelling, which it says on the control socket
+// right after. TCP streams have no other way to tell, they just close
+fn sender_cancelled(socket: &UdpSocket, sender_ip: IpAddr, link: Link) -> bool {
+    if socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .is_err()
+    {
+        return false;
+    }
+    let mut buf = [0; 1400];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((size, source)) if source.ip() == sender_ip => {
+                if let Some(Message::Abort) = link.open(&buf[..size]) {
+                    return true;
+                }
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+// Keeps answering the Start the sender repeats while it hasn't heard the ports, until up says
+// the data started coming in or the receive is over
+fn answer_repeats(
+    socket: &UdpSocket,
+    source: SocketAddr,
+    link: Link,
+    ports: &[u16],
+    up: &AtomicBool,
+) {
+    while !up.load(Ordering::Relaxed) {
+        if let Err(e) = answer_starts(socket, source, link, ports) {
+            status_err!("Failed to answer the announcement: {}", e);
+            return;
+        }
+        thread::sleep(START_POLL);
+    }
+}
+
+fn cancelled() -> SndError {
+    SndError::TransferFailed("The sender cancelled the transfer".to_string())
+}
+
+// Receives the data of one file into `sink` with whichever send method the offer uses. Returns
+// how many bytes were still missing when the transfer stopped
+#[allow(clippy::too_many_arguments)]
+fn receive_into(
+    socket: &UdpSocket,
+    source: SocketAddr,
+    send_method: &str,
+    sink: &mut impl Write,
+    expected: u64,
+    link: Link,
+    progress: &Progress,
+    on_write: impl FnMut(u64),
+) -> Result<u64, SndError> {
+    if send_method == "tcp" {
+        let listener = TcpListener::bind(("0.0.0.0", 0))
+            .map_err(SndError::network("Failed to bind TCP listener"))?;
+        let port = listener
+            .local_addr()
+            .map_err(SndError::network("Failed to get TCP listener address"))?
+            .port();
+        // Tell the sender where to connect, it is waiting on the socket that sent Start
+        if let Err(e) = link.send(socket, &Message::Ports(vec![port]), source) {
+            status_err!("Failed to send TCP port: {}", e);
+        }
+        let mut on_write = on_write;
+        let up = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| answer_repeats(socket, source, link, &[port], &up));
+            let missing = receive_file_tcp(
+                &listener,
+                sink,
+                Some(expected),
+                source.ip(),
+                link,
+                progress,
+                |written| {
+                    up.store(true, Ordering::Relaxed);
+                    on_write(written)
+                },
+            );
+            up.store(true, Ordering::Relaxed);
+            missing
+        })
+    } else {
+        // Nothing to connect to, the data comes to the socket Start was sent to. The sender keeps
+        // announcing the file until it hears this
+        if let Err(e) = link.send(socket, &Message::Ports(Vec::new()), source) {
+            status_err!("Failed to answer the announcement: {}", e);
+        }
+        receive_file_udp(
+            socket,
+            sink,
+            Some(expected),
+            send_method,
+            source.ip(),
+            link,
+            progress,
+            on_write,
+        )
+    }
+}
+
+// Receives a directory streamed as a tar, unpacking it into the downloads folder as it arrives
+// instead of storing the archive first. Returns whether it arrived complete and intact
+fn receive_directory(
+    socket: &UdpSocket,
+    source: SocketAddr,
+    send_method: &str,
+    file: &OfferedFile,
+    dest: &Path,
+    link: Link,
+    watch: Watch,
+) -> Result<bool, SndError> {
+    status!(
+        "{} {} {} {}",
+        "Receiving directory".green(),
+        file.path.blue(),
+        "through".green(),
+        send_method.blue()
+    );
+    // Senders fall back to windowed for directories in parallel offers
+    let send_method = if send_method == "parallel" {
+        "windowed"
+    } else {
+        send_method
+    };
+    // Unpacked next to the destination and only moved into place once complete, so a directory
+    // being overwritten survives a failed transfer. Directories can't be resumed, anything left
+    // over from an earlier attempt goes
+    let partial = partial_path(dest);
+    make_room(&partial)?;
+    create_dir_all(&partial).map_err(SndError::io(format!(
+        "Failed to create {}",
+        partial.display()
+    )))?;
+    let mut sink =
+        UnpackSink::new(&partial, file.size).map_err(SndError::io("Failed to start unpacking"))?;
+    let progress = Progress::receiving(watch);
+    let missing = receive_into(
+        socket,
+        source,
+        send_method,
+        &mut sink,
+        file.size,
+        link,
+        &progress,
+        |_| {},
+    );
+    let stats = progress.finish(matches!(missing, Ok(0)));
+    let missing = missing?;
+    let (actual, unpacked) = sink.finish();
+    stats.report(&file.path, Some(&actual));
+    if missing > 0 {
+        status!(
+            "{} {} {} {}",
+            "Transfer stopped after".yellow(),
+            human_readable_size(stats.bytes),
+            "of".yellow(),
+            human_readable_size(file.size)
+        );
+        status!(
+            "{} {}",
+            "Directories can't be resumed, what arrived is in".yellow(),
+            partial.display()
+        );
+        return Ok(false);
+    }
+    let skipped = match unpacked {
+        Ok(skipped) => skipped,
+        Err(e) => {
+            status_err!("Failed to unpack directory: {}", e);
+            status_err!("What was unpacked is in: {}", partial.display());
+            return Ok(false);
+        }
+    };
+    if let Some(expected) = &file.digest {
+        if &actual != expected {
+            let corrupt = corrupt_path(dest);
+            rename(&partial, &corrupt)
+                .map_err(SndError::io("Failed to quarantine corrupt directory"))?;
+            status_err!(
+                "{}
+  expected {}
+  got      {}
+{} {}",
+                "Checksum mismatch, the received directory is corrupt!"
+                    .red()
+                    .bold(),
+                expected,
+                actual,
+                "It was moved to".red(),
+                corrupt.display()
+            );
+            return Ok(false);
+        }
+        status!("{}", "Checksum verified".green());
+    }
+    make_room(dest)?;
+    rename(&partial, dest).map_err(SndError::io("Failed to move directory into place"))?;
+    report_skipped(dest, &skipped);
+    Ok(true)
+}
+
+// Receives one file after its Start, then verifies it and moves it into place. Returns whether it
+// arrived complete and intact
+fn receive_file(
+    socket: &UdpSocket,
+    source: SocketAddr,
+    dm: &DM,
+    file: &OfferedFile,
+    mut journal: Journal,
+    link: Link,
+    watch: Watch,
+) -> Result<bool, SndError> {
+    let offset = journal.resume_offset();
+    status!(
+        "{} {} {} {}",
+        "Receiving".green(),
+        file.path.blue(),
+        "through".green(),
+        dm.send_method.blue()
+    );
+    let saved_path = journal.destination().to_path_buf();
+    let mut fp = downloadfc(&saved_path, offset)?;
+    let progress = Progress::receiving(watch);
+    let missing = if dm.send_method == "parallel" {
+        let sockets = (0..dm.streams)
+            .map(|_| UdpSocket::bind(("0.0.0.0", 0)))
+            .collect::<io::Result<Vec<UdpSocket>>>()
+            .map_err(SndError::network("Failed to bind a socket"))?;
+        let ports = sockets
+            .iter()
+            .map(|s| s.local_addr().map(|a| a.port()))
+            .collect::<io::Result<Vec<u16>>>()
+            .map_err(SndError::network("Failed to get socket address"))?;
+        // Tell the sender where to send each stream, it is waiting on the socket that sent Start
+        if let Err(e) = link.send(socket, &Message::Ports(ports.clone()), source) {
+            status_err!("Failed to send parallel ports: {}", e);
+        }
+        let journal = Mutex::new(&mut journal);
+        let up = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| answer_repeats(socket, source, link, &ports, &up));
+            let missing = receive_file_parallel(
+                &sockets,
+                &fp,
+                file.size,
+                source.ip(),
+                link,
+                &progress,
+                |start, end| {
+                    up.store(true, Ordering::Relaxed);
+                    journal.lock().unwrap().record(start, end)
+                },
+            );
+            up.store(true, Ordering::Relaxed);
+            missing
+        })
+    } else {
+        // Only what is left after the resume offset is sent again
+        receive_into(
+            socket,
+            source,
+            &dm.send_method,
+            &mut fp,
+            file.size.saturating_sub(offset),
+            link,
+            &progress,
+            |written| journal.record(offset, offset + written),
+        )
+    };
+    let stats = progress.finish(matches!(missing, Ok(0)));
+    let missing = missing.and_then(|missing| {
+        fp.flush().map_err(SndError::io("Failed to flush file"))?;
+        Ok(missing)
+    });
+    drop(fp);
+    let missing = match missing {
+        Ok(missing) => missing,
+        Err(e) => {
+            // Whatever did make it to disk can still be resumed from
+            let _ = journal.save();
+            return Err(e);
+        }
+    };
+    if missing > 0 {
+        stats.report(&file.path, None);
+        status!(
+            "{} {} {} {}",
+            "Transfer stopped after".yellow(),
+            human_readable_size(offset + stats.bytes),
+            "of".yellow(),
+            human_readable_size(file.size)
+        );
+        if let Err(e) = journal.save() {
+            status_err!("Failed to save transfer journal: {}", e);
+        }
+        status!(
+            "{} {}",
+            "Transfer incomplete, it will resume from here next time. Partial file:".yellow(),
+            partial_path(&saved_path).display()
+        );
+        return Ok(false);
+    }
+    journal.remove();
+    let actual = file
+        .digest
+        .as_ref()
+        .map(|_| file_digest(&partial_path(&saved_path)))
+        .transpose()
+        .map_err(SndError::io("Failed to calculate checksum"))?;
+    stats.report(&file.path, actual.as_deref());
+    if let (Some(expected), Some(actual)) = (&file.digest, &actual) {
+        if actual != expected {
+            // Keep the data around for inspection but never under the real name
+            let corrupt = corrupt_path(&saved_path);
+            rename(partial_path(&saved_path), &corrupt)
+                .map_err(SndError::io("Failed to quarantine corrupt file"))?;
+            status_err!(
+                "{}
+  expected {}
+  got      {}
+{} {}",
+                "Checksum mismatch, the received file is corrupt!"
+                    .red()
+                    .bold(),
+                expected,
+                actual,
+                "It was moved to".red(),
+                corrupt.display()
+            );
+            return Ok(false);
+        }
+        status!("{}", "Checksum verified".green());
+    }
+    make_room(&saved_path)?;
+    rename(partial_path(&saved_path), &saved_path)
+        .map_err(SndError::io("Failed to move partial file into place"))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, os::unix::fs::symlink, process};
+
+    // An empty folder of its own for each test, holding "taken.txt"
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("snd-receiver-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        fs::write(dir.join("taken.txt"), b"already here").unwrap();
+        dir
+    }
+
+    fn settle(dir: &Path, names: &[&str], policy: Collision) -> Vec<(usize, PathBuf)> {
+        let placed = names.iter().map(|name| dir.join(name)).enumerate();
+        settle_collisions(placed, |_| policy)
+    }
+
+    #[test]
+    fn renames_around_existing_files() {
+        let dir = scratch("rename");
+        fs::write(dir.join("taken (1).txt"), b"also here").unwrap();
+        let settled = settle(&dir, &["taken.txt", "free.txt"], Collision::Rename);
+        assert_eq!(
+            settled,
+            [(0, dir.join("taken (2).txt")), (1, dir.join("free.txt"))]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renames_around_earlier_files_of_the_offer() {
+        let dir = scratch("rename-offer");
+        let settled = settle(&dir, &["new.tar.gz", "new.tar.gz"], Collision::Rename);
+        assert_eq!(
+            settled,
+            [(0, dir.join("new.tar.gz")), (1, dir.join("new (1).tar.gz"))]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overwrites_in_place() {
+        let dir = scratch("overwrite");
+        let settled = settle(&dir, &["taken.txt", "free.txt"], Collision::Overwrite);
+        assert_eq!(
+            settled,
+            [(0, dir.join("taken.txt")), (1, dir.join("free.txt"))]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_what_is_in_the_way() {
+        let dir = scratch("skip");
+        // A link to nothing is still in the way
+        symlink(dir.join("missing"), dir.join("dangling.txt")).unwrap();
+        let names = ["taken.txt", "dangling.txt", "free.txt", "free.txt"];
+        let settled = settle(&dir, &names, Collision::Skip);
+        assert_eq!(settled, [(2, dir.join("free.txt"))]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn asks_only_about_collisions() {
+        let dir = scratch("ask");
+        let mut asked = Vec::new();
+        let placed = ["taken.txt", "free.txt"].map(|name| dir.join(name));
+        settle_collisions(placed.into_iter().enumerate(), |path| {
+            asked.push(path.to_path_buf());
+            Collision::Skip
+        });
+        assert_eq!(asked, [dir.join("taken.txt")]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,754 @@
+use crate::{
+    archive::{tar_reader, tar_summary},
+    crypto::{Pake, Role, SEAL_OVERHEAD, Session, gen_pairing_code},
+    error::SndError,
+    identity::{load_identity, sign_offer},
+    network::{
+        MAX_DATAGRAM, MAX_PAYLOAD, PORT, announce, send_file, send_file_parallel, send_stream,
+    },
+    progress::Snapshot,
+    protocol::{Link, MIN_VERSION, Message, Offer, VERSION, negotiate},
+    types::{Config, HostInfo, OfferedFile},
+    utils::{file_digest, gen_cname, get_file_type, human_readable_size},
+};
+use colored::Colorize;
+use rand_core::{OsRng, RngCore};
+use std::{
+    fs::File,
+    io,
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+// How many wrong pairing codes a sender puts up with before ignoring further attempts
+const MAX_PAIRING_ATTEMPTS: u32 = 3;
+// Room the answer to pairing takes around the sealed offer, the pake message and framing
+const PAIRING_OVERHEAD: usize = 64;
+
+// How often waiting for an answer to an offer checks whether it was cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(200);
+
+/// Cancels an offer or a transfer from another thread, such as a Ctrl-C handler. Get one from
+/// [`PendingOffer::cancel_handle`], it stays valid once the offer is accepted
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    /// Withdraws the offer if it is still waiting, or stops the transfer and tells the receiver
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A file or directory ready to be offered. Directories are streamed as a tar built on the fly,
+/// so their size and checksum are those of that tar
+pub struct Outgoing {
+    pub path: PathBuf,
+    pub abspath: String,
+    pub ftype: String,
+    pub size: u64,
+    pub digest: String,
+}
+
+impl Outgoing {
+    /// Works out the type, size and SHA-256 of what is at `path`, which reads all of it. A
+    /// symbolic link is only sent (as what it points to) with `follow_symlinks`, otherwise it
+    /// fails with [`SndError::InvalidPath`]
+    pub fn prepare(path: PathBuf, follow_symlinks: bool) -> Result<Outgoing, SndError> {
+        let abspath: String = path.to_string_lossy().to_string();
+        let link = path
+            .symlink_metadata()
+            .map_err(SndError::io(format!("Failed to read {}", abspath)))?
+            .is_symlink();
+        if link && !follow_symlinks {
+            return Err(SndError::InvalidPath(format!(
+                "{} is a symbolic link, set follow_symlinks to send what it points to",
+                abspath
+            )));
+        }
+        let (ftype, size, digest) = if path.is_dir() {
+            let (size, digest) =
+                tar_summary(&path).map_err(SndError::io(format!("Failed to read {}", abspath)))?;
+            ("directory".to_string(), size, digest)
+        } else {
+            // The exact length of what is sent rather than the space taken on disk, the receiver
+            // holds the streams of a parallel transfer to it
+            let size = File::open(&path)
+                .and_then(|file| file.metadata())
+                .map_err(SndError::io(format!("Failed to read {}", abspath)))?
+                .len();
+            let digest =
+                file_digest(&path).map_err(SndError::io(format!("Failed to read {}", abspath)))?;
+            (get_file_type(&path).to_string(), size, digest)
+        };
+        Ok(Outgoing {
+            path,
+            abspath,
+            ftype,
+            size,
+            digest,
+        })
+    }
+}
+
+/// Offers files to receivers and sends the ones they accept
+pub struct Sender {
+    config: Config,
+}
+
+impl Sender {
+    /// The send method, stream count and whether to encrypt come from `config`. How long to wait
+    /// on an unresponsive receiver is always read from the config file
+    pub fn new(config: Config) -> Sender {
+        Sender { config }
+    }
+
+    /// Starts listening for the announcements receivers broadcast every couple of seconds. The
+    /// [`Discovery`] holds the snd port, so it has to be dropped before making an offer
+    pub fn discover() -> Result<Discovery, SndError> {
+        let socket = UdpSocket::bind(("0.0.0.0", PORT))
+            .map_err(SndError::network("Failed to bind to port"))?;
+        Ok(Discovery {
+            socket,
+            seen: Vec::new(),
+        })
+    }
+
+    /// Offers `files` to `host`, in the protocol version agreed on when it was discovered. The
+    /// offer stands for `timeout`, the receiver is told so it can drop the offer after that.
+    /// Nothing is sent until it accepts, see [`PendingOffer::wait`]. The offer has to fit in one
+    /// datagram, listing too many files fails with [`SndError::Usage`]
+    pub fn offer(
+        &self,
+        host: &HostInfo,
+        files: Vec<Outgoing>,
+        timeout: Duration,
+    ) -> Result<PendingOffer, SndError> {
+        let identity = load_identity()?;
+        let id = OsRng.next_u32();
+        let expires_in = timeout.as_secs().min(u32::MAX as u64) as u32;
+        let offer = Offer {
+            name: gen_cname(),
+            id,
+            expires_in,
+            sealed: false,
+            send_method: self.config.send_method.clone(),
+            // How many streams a parallel transfer is split into, the receiver opens one socket
+            // for each
+            streams: self.config.parallel_streams.clamp(1, u16::MAX as u64) as u16,
+            // Directories are streamed as a plain tar rather than sent as a .tar.gz
+            files: files
+                .iter()
+                .map(|entry| OfferedFile {
+                    path: entry.abspath.clone(),
+                    file_type: entry.ftype.clone(),
+                    size: entry.size,
+                    digest: Some(entry.digest.clone()),
+                    streamed: entry.ftype == "directory",
+                })
+                .collect(),
+        };
+        let signature = Some(sign_offer(&identity, &offer));
+        let offer = Message::Offer { offer, signature }.encode(host.version);
+        // Encrypted offers only reveal the hostname until the receiver proves it has the
+        // pairing code
+        let pairing_code = self.config.encrypt.then(gen_pairing_code);
+        // The whole offer goes out in one datagram, sealed inside the answer to pairing when
+        // encrypted
+        let size = match pairing_code {
+            Some(_) => offer.len() + SEAL_OVERHEAD + PAIRING_OVERHEAD,
+            None => offer.len(),
+        };
+        if size > MAX_PAYLOAD {
+            return Err(SndError::Usage(format!(
+                "The offer of {} files is {} bytes, more than the {} that fit in one datagram. Send fewer at a time",
+                files.len(),
+                size,
+                MAX_PAYLOAD
+            )));
+        }
+        // Listen before offering, a daemon accepts straight away
+        let socket = UdpSocket::bind(("0.0.0.0", PORT))
+            .map_err(SndError::network("Failed to bind to port"))?;
+        let sent = if pairing_code.is_some() {
+            let sealed = Offer {
+                name: gen_cname(),
+                id,
+                expires_in,
+                sealed: true,
+                send_method: String::new(),
+                streams: 1,
+                files: Vec::new(),
+            };
+            let signature = Some(sign_offer(&identity, &sealed));
+            let sealed = Message::Offer {
+                offer: sealed,
+                signature,
+            };
+            socket.send_to(&sealed.encode(host.version), (host.ip, PORT))
+        } else {
+            socket.send_to(&offer, (host.ip, PORT))
+        };
+        sent.map_err(SndError::network(format!(
+            "Failed to send the offer to {}",
+            host.ip
+        )))?;
+        status!("Sent to {}", host.ip);
+        Ok(PendingOffer {
+            socket,
+            target: SocketAddr::new(host.ip, PORT),
+            id,
+            cancel: Cancel::default(),
+            deadline: Instant::now() + timeout,
+            offer,
+            version: host.version,
+            pairing_code,
+            files,
+            send_method: self.config.send_method.clone(),
+        })
+    }
+}
+
+/// Receivers heard from so far, see [`Sender::discover`]
+pub struct Discovery {
+    socket: UdpSocket,
+    seen: Vec<String>,
+}
+
+impl Discovery {
+    /// Waits up to `timeout` for a receiver that hasn't been seen yet, None if none showed up
+    pub fn next_host(&mut self, timeout: Duration) -> Result<Option<HostInfo>, SndError> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0; 1024];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            self.socket
+                .set_read_timeout(Some(left))
+                .map_err(SndError::network("Failed to set read timeout"))?;
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, source)) => {
+                    let Some(Message::Hello {
+                        name,
+                        min_version,
+                        max_version,
+                    }) = Message::decode(&buf[..size])
+                    else {
+                        continue;
+                    };
+                    if self.seen.contains(&name) {
+                        continue;
+                    }
+                    self.seen.push(name.clone());
+                    let Some(version) = negotiate(min_version, max_version) else {
+                        status_err!(
+                            "{} speaks protocol versions {} to {}, this snd only {} to {}",
+                            name,
+                            min_version,
+                            max_version,
+                            MIN_VERSION,
+                            VERSION
+                        );
+                        continue;
+                    };
+                    return Ok(Some(HostInfo {
+                        name,
+                        ip: source.ip(),
+                        version,
+                    }));
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted
+                            | io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(SndError::Network("Receive error".to_string(), e)),
+            }
+        }
+    }
+}
+
+/// An offer waiting for the receiver to accept it
+pub struct PendingOffer {
+    socket: UdpSocket,
+    // The receiver's port and the id it knows the offer by, for withdrawing it
+    target: SocketAddr,
+    id: u32,
+    cancel: Cancel,
+    // When the offer lapses, as told to the receiver
+    deadline: Instant,
+    // The encoded offer frame, sent sealed once paired if the offer is encrypted
+    offer: Vec<u8>,
+    version: u8,
+    pairing_code: Option<String>,
+    files: Vec<Outgoing>,
+    send_method: String,
+}
+
+impl PendingOffer {
+    /// The code the receiver has to type in to accept an encrypted offer
+    pub fn pairing_code(&self) -> Option<&str> {
+        self.pairing_code.as_deref()
+    }
+
+    /// A handle that cancels the offer while [`PendingOffer::wait`] is waiting, and the transfer
+    /// once it is accepted
+    pub fn cancel_handle(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    /// Takes the offer back so it disappears from the receiver's list of offers
+    pub fn withdraw(self) {
+        let withdraw = Message::Withdraw { id: self.id };
+        match self
+            .socket
+            .send_to(&withdraw.encode(self.version), self.target)
+        {
+            Ok(_) => status!("{}", "Offer withdrawn".yellow()),
+            Err(e) => status_err!("Failed to withdraw the offer: {}", e),
+        }
+    }
+
+    /// Waits for the receiver to accept, pairing with it first if the offer is encrypted. Fails
+    /// with [`SndError::Declined`] as soon as the receiver turns it down, with
+    /// [`SndError::NotAccepted`] if it stays quiet until the offer lapses, or with
+    /// [`SndError::Cancelled`] once the offer is cancelled and withdrawn
+    pub fn wait(self) -> Result<Accepted, SndError> {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut session: Option<Session> = None;
+        let mut pairing_attempts = 0;
+
+        loop {
+            // Counted from when the offer went out, the receiver expires it at the same time
+            let left = self.deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(SndError::NotAccepted);
+            }
+            if self.cancel.is_cancelled() {
+                self.withdraw();
+                return Err(SndError::Cancelled);
+            }
+            // Woken up every so often to notice a cancel
+            self.socket
+                .set_read_timeout(Some(left.min(CANCEL_POLL)))
+                .map_err(SndError::network("Failed to set timeout"))?;
+            let (size, source) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == io::ErrorKind::Interrupted
+                        || e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(SndError::Network("Receive error".to_string(), e)),
+            };
+            // Only the host the offer went to can pair with it, accept it or turn it down,
+            // otherwise whoever on the network answers first would be sent the files or could
+            // call the offer off
+            if source.ip() != self.target.ip() {
+                continue;
+            }
+            let link = Link {
+                version: self.version,
+                session: session.as_ref(),
+                cancel: None,
+            };
+            // Pairing requests and rejections come in the clear even once a session is set up,
+            // the receiver tries again after a mistyped code and may still turn the offer down.
+            // Everything else is sealed after pairing
+            let message = match Message::decode(&buf[..size]) {
+                Some(message @ (Message::Pake { .. } | Message::Reject { .. })) => message,
+                _ => match link.open(&buf[..size]) {
+                    Some(message) => message,
+                    None => continue,
+                },
+            };
+            match message {
+                Message::Reject { from, reason } => {
+                    return Err(SndError::Declined { by: from, reason });
+                }
+                Message::Pake { message: peer, .. } if self.pairing_code.is_some() => {
+                    // Each attempt is one guess at the code, so only allow a few
+                    if let Some(code) = &self.pairing_code
+                        && pairing_attempts < MAX_PAIRING_ATTEMPTS
+                    {
+                        pairing_attempts += 1;
+                        session = pair_with_receiver(
+                            &self.socket,
+                            source,
+                            code,
+                            &peer,
+                            &self.offer,
+                            self.version,
+                        );
+                    }
+                }
+                // Only the paired receiver can accept an encrypted offer
+                _ if self.pairing_code.is_some() && session.is_none() => {}
+                Message::Accept { from, files } => {
+                    let accepted: Vec<(usize, u64)> = files
+                        .into_iter()
+                        .filter(|&(i, _)| i < self.files.len())
+                        .collect();
+                    if accepted.is_empty() {
+                        continue;
+                    }
+                    return Ok(Accepted {
+                        socket: self.socket,
+                        source,
+                        cancel: self.cancel,
+                        session,
+                        version: self.version,
+                        receiver: from,
+                        files: self.files,
+                        accepted,
+                        send_method: self.send_method,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// An offer the receiver accepted some or all of the files of
+pub struct Accepted {
+    socket: UdpSocket,
+    source: SocketAddr,
+    cancel: Cancel,
+    session: Option<Session>,
+    version: u8,
+    receiver: String,
+    files: Vec<Outgoing>,
+    // Index into files and where to resume each accepted one
+    accepted: Vec<(usize, u64)>,
+    send_method: String,
+}
+
+impl Accepted {
+    /// Hostname of the receiver that accepted
+    pub fn receiver(&self) -> &str {
+        &self.receiver
+    }
+
+    /// The files that were accepted, with how many bytes of each the receiver already has
+    pub fn files(&self) -> impl Iterator<Item = (&Outgoing, u64)> {
+        self.accepted
+            .iter()
+            .map(|&(i, resume)| (&self.files[i], resume))
+    }
+
+    /// Backs out without sending anything, the receiver stops waiting for the files
+    pub fn abort(self) {
+        self.tell_aborted();
+    }
+
+    /// Sends the accepted files one after another. `on_progress` is called with the position
+    /// of the file in [`Accepted::files`] and how far along it is. Fails with
+    /// [`SndError::Cancelled`] once the transfer is cancelled through its [`Cancel`] handle
+    pub fn send(self, on_progress: impl Fn(usize, &Snapshot) + Sync) -> Result<(), SndError> {
+        for (n, &(i, resume)) in self.accepted.iter().enumerate() {
+            let entry = &self.files[i];
+            let watch = |snapshot: &Snapshot| on_progress(n, snapshot);
+            let sent = match self.send_entry(i, resume, &watch) {
+                Err(SndError::Cancelled) => {
+                    self.tell_aborted();
+                    return Err(SndError::Cancelled);
+                }
+                sent => sent?,
+            };
+            // Once one file fails the receiver has most likely gone, the rest would only time
+            // out as well
+            if !sent {
+                return Err(SndError::TransferFailed(format!(
+                    "{} was not sent completely",
+                    entry.abspath
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // The receiver waits for the next Start on its control socket, an Abort there ends the
+    // transfer on its side. A data stream that was cut short has had its own Abort already
+    fn tell_aborted(&self) {
+        let link = Link {
+            version: self.version,
+            session: self.session.as_ref(),
+            cancel: None,
+        };
+        if let Err(e) = link.send(&self.socket, &Message::Abort, self.source) {
+            status_err!("Failed to tell the receiver: {}", e);
+        }
+    }
+
+    // Sends one accepted file, announcing it with Start first. Returns whether all of it was sent
+    fn send_entry(
+        &self,
+        index: usize,
+        resume: u64,
+        watch: &(dyn Fn(&Snapshot) + Sync),
+    ) -> Result<bool, SndError> {
+        let entry = &self.files[index];
+        let (socket, source) = (&self.socket, self.source);
+        let link = Link {
+            version: self.version,
+            session: self.session.as_ref(),
+            cancel: Some(&self.cancel),
+        };
+        let open = || {
+            File::open(&entry.path)
+                .map_err(SndError::io(format!("Failed to open {}", entry.abspath)))
+        };
+        let Some(ports) = announce(socket, source, link, index)? else {
+            status!(
+                "{}",
+                "Receiver never answered the announcement. Timing out...".yellow()
+            );
+            return Ok(false);
+        };
+        status!("Announced {} to {}", entry.abspath, source);
+        let targets: Vec<SocketAddr> = ports
+            .into_iter()
+            .map(|port| SocketAddr::new(source.ip(), port))
+            .collect();
+        let mut send_method = self.send_method.clone();
+        // Streamed directories can only be written in order, so they go windowed instead of
+        // parallel
+        if send_method == "parallel" && entry.ftype == "directory" {
+            send_method = "windowed".to_string();
+        }
+        if resume > 0 {
+            status!(
+                "{} {}",
+                "Receiver already has".yellow(),
+                human_readable_size(resume).blue()
+            );
+        }
+        // Parallel transfers go to the sockets the receiver answered Start with
+        if send_method == "parallel" {
+            if targets.is_empty() {
+                status!(
+                    "{}",
+                    "Receiver never sent its parallel ports. Timing out...".yellow()
+                );
+                return Ok(false);
+            }
+            let stats = send_file_parallel(open()?, resume, &targets, link, watch)?;
+            stats.report(&entry.abspath, Some(&entry.digest));
+            return Ok(stats.complete);
+        }
+        // TCP transfers go to the port the receiver answered Start with
+        let target = if send_method == "tcp" {
+            targets.first().copied()
+        } else {
+            Some(source)
+        };
+        match target {
+            // Directories are tarred while they are sent and always start from the beginning
+            Some(target) if entry.ftype == "directory" => {
+                let (reader, builder) = tar_reader(&entry.path)
+                    .map_err(SndError::io(format!("Failed to read {}", entry.abspath)))?;
+                let stats = send_stream(reader, entry.size, target, &send_method, link, watch)?;
+                stats.report(&entry.abspath, Some(&entry.digest));
+                // An aborted transfer stops reading the tar, the builder fails on that by itself
+                if !stats.complete {
+                    return Ok(false);
+                }
+                match builder.join() {
+                    Ok(Ok(())) => Ok(true),
+                    Ok(Err(e)) => Err(SndError::Io(format!("Failed to tar {}", entry.abspath), e)),
+                    Err(_) => Ok(false),
+                }
+            }
+            Some(target) => {
+                let stats = send_file(open()?, target, send_method, resume, link, watch)?;
+                stats.report(&entry.abspath, Some(&entry.digest));
+                Ok(stats.complete)
+            }
+            None => {
+                status!(
+                    "{}",
+                    "Receiver never sent a TCP port. Timing out...".yellow()
+                );
+                Ok(false)
+            }
+        }
+    }
+}
+
+// Answers a receiver's pairing request with our half of the exchange and the real offer, sealed
+// so that only a receiver that typed in the right code can read it
+fn pair_with_receiver(
+    socket: &UdpSocket,
+    source: SocketAddr,
+    code: &str,
+    peer: &[u8],
+    offer: &[u8],
+    version: u8,
+) -> Option<Session> {
+    let pake = Pake::start(Role::Sender, code);
+    let message = pake.message().to_vec();
+    let session = pake.finish(peer)?;
+    let reply = Message::Pake {
+        message,
+        sealed_offer: session.seal(offer),
+    };
+    if let Err(e) = socket.send_to(&reply.encode(version), source) {
+        status_err!("Failed to answer pairing request: {}", e);
+        return None;
+    }
+    Some(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const CODE: &str = "4821-crimson-otter";
+
+    // An encrypted offer of one file to the receiver listening on rx
+    fn pending(rx: &UdpSocket) -> PendingOffer {
+        PendingOffer {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            target: rx.local_addr().unwrap(),
+            id: 1,
+            cancel: Cancel::default(),
+            deadline: Instant::now() + Duration::from_secs(5),
+            offer: b"the offer".to_vec(),
+            version: VERSION,
+            pairing_code: Some(CODE.to_string()),
+            files: vec![Outgoing {
+                path: PathBuf::from("a.bin"),
+                abspath: "a.bin".to_string(),
+                ftype: "Binary file".to_string(),
+                size: 1,
+                digest: String::new(),
+            }],
+            send_method: "tcp".to_string(),
+        }
+    }
+
+    fn receiver() -> UdpSocket {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        rx.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        rx
+    }
+
+    // The receiver's half of pairing, the session if the code opened the offer
+    fn pair(rx: &UdpSocket, sender: SocketAddr, code: &str) -> Option<Session> {
+        let pake = Pake::start(Role::Receiver, code);
+        let request = Message::Pake {
+            message: pake.message().to_vec(),
+            sealed_offer: Vec::new(),
+        };
+        rx.send_to(&request.encode(VERSION), sender).unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let (size, _) = rx.recv_from(&mut buf).unwrap();
+        let Some(Message::Pake {
+            message,
+            sealed_offer,
+        }) = Message::decode(&buf[..size])
+        else {
+            panic!("the sender didn't answer the pairing request");
+        };
+        let session = pake.finish(&message)?;
+        (session.open(&sealed_offer)? == b"the offer").then_some(session)
+    }
+
+    #[test]
+    fn pairs_again_after_a_wrong_code() {
+        let rx = receiver();
+        let offer = pending(&rx);
+        let sender = offer.socket.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            assert!(pair(&rx, sender, "0000-wrong-code").is_none());
+            let session = pair(&rx, sender, CODE).expect("the right code should open the offer");
+            let accept = Message::Accept {
+                from: "bravo".to_string(),
+                files: vec![(0, 0)],
+            };
+            rx.send_to(&session.seal(&accept.encode(VERSION)), sender)
+                .unwrap();
+        });
+        match offer.wait() {
+            Ok(accepted) => assert_eq!(accepted.receiver(), "bravo"),
+            Err(e) => panic!("the offer wasn't accepted: {}", e),
+        }
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn takes_a_rejection_after_pairing() {
+        let rx = receiver();
+        let offer = pending(&rx);
+        let sender = offer.socket.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            pair(&rx, sender, CODE).expect("the right code should open the offer");
+            let reject = Message::Reject {
+                from: "bravo".to_string(),
+                reason: "not now".to_string(),
+            };
+            rx.send_to(&reject.encode(VERSION), sender).unwrap();
+        });
+        match offer.wait() {
+            Err(SndError::Declined { by, reason }) => {
+                assert_eq!(by, "bravo");
+                assert_eq!(reason, "not now");
+            }
+            Ok(_) => panic!("the offer was accepted"),
+            Err(e) => panic!("the rejection was missed: {}", e),
+        }
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn reports_who_declined_and_why() {
+        let rx = receiver();
+        let mut offer = pending(&rx);
+        offer.pairing_code = None;
+        let sender = offer.socket.local_addr().unwrap();
+        let reject = Message::Reject {
+            from: "bravo".to_string(),
+            reason: "Too large".to_string(),
+        };
+        rx.send_to(&reject.encode(VERSION), sender).unwrap();
+        let err = offer.wait().err().expect("the offer was accepted");
+        assert_eq!(err.to_string(), "bravo declined the offer: Too large");
+        assert_eq!(err.exit_code(), 77);
+    }
+
+    #[test]
+    fn ignores_answers_from_other_hosts() {
+        let rx = receiver();
+        let mut offer = pending(&rx);
+        // Pretend the offer went to another host on the same port
+        offer.target = SocketAddr::new("127.0.0.2".parse().unwrap(), offer.target.port());
+        offer.deadline = Instant::now() + Duration::from_millis(500);
+        let sender = offer.socket.local_addr().unwrap();
+        let reject = Message::Reject {
+            from: "mallory".to_string(),
+            reason: String::new(),
+        };
+        rx.send_to(&reject.encode(VERSION), sender).unwrap();
+        assert!(matches!(offer.wait(), Err(SndError::NotAccepted)));
+    }
+}
@@ -1,4 +1,4 @@
-use std::ffi::{c_char, CString};
+use std::ffi::{CString, c_char};
 
 unsafe extern "C" {
     pub fn initTUI();
@@ -7,10 +7,11 @@ unsafe extern "C" {
     pub fn setHostnames(hostnames: *mut *const c_char, count: i32);
 }
 
-pub fn update_tui_hostnames(hostnames: &Vec<String>) {
+pub fn update_tui_hostnames(hostnames: &[String]) {
     let c_strings: Vec<CString> = hostnames
         .iter()
-        .map(|s| CString::new(s.as_str()).expect("CString::new failed"))
+        // Hostnames come off the network, a NUL in one would end it early in C anyway
+        .map(|s| CString::new(s.replace('\0', "")).unwrap_or_default())
         .collect();
     let mut pointers: Vec<*const c_char> = c_strings.iter().map(|cs| cs.as_ptr()).collect();
     unsafe {
@@ -1,26 +1,74 @@
-use std::{fmt, net::IpAddr};
+use std::{
+    fmt,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct HostInfo {
     pub name: String,
     pub ip: IpAddr,
+    // Wire format version to talk to it in
+    pub version: u8,
 }
 
-#[derive(Debug)]
+// One entry of an offer's manifest
+#[derive(Clone, Debug, PartialEq)]
+pub struct OfferedFile {
+    pub path: String,
+    pub file_type: String,
+    pub size: u64,
+    pub digest: Option<String>,
+    // Directory sent as a plain tar that is unpacked as it arrives, every directory is
+    pub streamed: bool,
+}
+
+#[derive(Clone, Debug)]
 pub struct DM {
+    // Chosen by the sender, tells a resent copy of an offer from a new one
+    pub id: u32,
+    pub received: SystemTime,
+    // When the sender stops waiting for an answer
+    pub expires: SystemTime,
     pub host_info: HostInfo,
     pub send_method: String,
-    pub file_path: String,
-    pub file_type: String,
-    pub file_size: u64,
+    // How many streams a parallel transfer is split into
+    pub streams: usize,
+    pub files: Vec<OfferedFile>,
+    // Encrypted offer whose details are only known after pairing
+    pub sealed: bool,
+    // Sender's public key, only set if the offer carried a valid signature
+    pub identity: Option<String>,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum ShModes {
     REC,
     SND,
 }
 
+// What to do when something already exists where a received file or directory would be saved
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collision {
+    // Save it next to the existing one with a number added, "name (1).ext"
+    Rename,
+    Overwrite,
+    Skip,
+}
+
+impl Collision {
+    // The config's on_collision value, None for "prompt" which leaves it to whoever is accepting
+    pub fn from_config(value: &str) -> Option<Collision> {
+        match value {
+            "overwrite" => Some(Collision::Overwrite),
+            "skip" => Some(Collision::Skip),
+            "prompt" => None,
+            _ => Some(Collision::Rename),
+        }
+    }
+}
+
 impl fmt::Display for ShModes {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -29,21 +77,127 @@ impl fmt::Display for ShModes {
 
 impl fmt::Display for DM {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let size_str = crate::utils::human_readable_size(self.file_size);
+        if self.sealed {
+            return write!(
+                f,
+                "From {} with ip {}: encrypted offer, accept it with the pairing code to see the file",
+                self.host_info.name, self.host_info.ip
+            );
+        }
+        if let [file] = self.files.as_slice() {
+            let size_str = crate::utils::human_readable_size(file.size);
+            return write!(
+                f,
+                "From {} with ip {} and {}: {} with size {} using send method: {}",
+                self.host_info.name,
+                self.host_info.ip,
+                file.file_type,
+                file.path,
+                size_str,
+                self.send_method
+            );
+        }
         write!(
             f,
-            "From {} with ip {} and {}: {} with size {} using send method: {}",
+            "From {} with ip {}: {} files with size {} using send method: {}",
             self.host_info.name,
             self.host_info.ip,
-            self.file_type,
-            self.file_path,
-            size_str,
+            self.files.len(),
+            crate::utils::human_readable_size(self.total_size()),
             self.send_method
-        )
+        )?;
+        for (i, file) in self.files.iter().enumerate() {
+            write!(
+                f,
+                "\n    {}) {} {} with size {}",
+                i + 1,
+                file.file_type,
+                file.path,
+                crate::utils::human_readable_size(file.size)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl DM {
+    pub fn total_size(&self) -> u64 {
+        // Sizes come from the sender, a made up one mustn't overflow the total
+        self.files
+            .iter()
+            .fold(0, |total: u64, f| total.saturating_add(f.size))
+    }
+
+    // How long ago the offer arrived
+    pub fn age(&self) -> Duration {
+        self.received.elapsed().unwrap_or_default()
+    }
+
+    // Whether the sender has stopped waiting, accepting it then can't work
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.expires
+    }
+
+    // Whether this is the same offer sent again: the same copy, or the same files from the same
+    // sender. Encrypted offers don't show their files, so only the same copy counts for those
+    pub fn is_resend_of(&self, other: &DM) -> bool {
+        if self.host_info.ip != other.host_info.ip
+            || self.host_info.name != other.host_info.name
+            || self.identity != other.identity
+        {
+            return false;
+        }
+        self.id == other.id
+            || (!self.sealed
+                && !other.sealed
+                && self.send_method == other.send_method
+                && self.files == other.files)
     }
 }
 
 pub struct Config {
     pub send_method: String,
     pub follow_symlinks: bool,
+    pub encrypt: bool,
+    // How many streams the parallel send method splits a file into
+    pub parallel_streams: u64,
+    // Daemon policy: hostnames whose offers are accepted unattended, offers from trusted peers
+    // always are. "*" accepts any host
+    pub auto_accept_hosts: Vec<String>,
+    // Largest offer the daemon accepts in bytes, 0 for no limit
+    pub max_file_size: u64,
+    // File types (as named by get_file_type) the daemon accepts, empty for any
+    pub allowed_file_types: Vec<String>,
+    // Bytes a single peer may send the daemon while it runs, 0 for no limit
+    pub peer_quota: u64,
+    // Times a sender resends the same packet without hearing back before giving up
+    pub max_retries: u64,
+    // Seconds either side waits without hearing from the other before giving up on a transfer
+    pub idle_timeout: u64,
+    // Folder received files are saved to, empty for the system downloads folder
+    pub download_dir: String,
+    // Folders for the files of particular senders, each keyed by hostname or identity fingerprint
+    pub sender_dirs: Vec<(String, String)>,
+    // What happens to received files that would replace something: rename, overwrite, skip or prompt
+    pub on_collision: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            send_method: "semi-reliable".to_string(),
+            follow_symlinks: false,
+            encrypt: false,
+            parallel_streams: 4,
+            auto_accept_hosts: Vec::new(),
+            max_file_size: 0,
+            allowed_file_types: Vec::new(),
+            peer_quota: 0,
+            max_retries: 10,
+            idle_timeout: 30,
+            download_dir: String::new(),
+            sender_dirs: Vec::new(),
+            on_collision: "rename".to_string(),
+        }
+    }
 }
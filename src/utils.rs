@@ -1,56 +1,139 @@
-use crate::types::Config;
+use crate::{
+    error::SndError,
+    identity::fingerprint,
+    journal::partial_path,
+    types::{Config, DM},
+};
 use dirs::{config_dir, download_dir};
-use flate2::{write::GzEncoder, Compression};
 use gethostname::gethostname;
+use glob::glob;
+use sha2::{Digest, Sha256};
 use std::{
-    env::{self, temp_dir}, ffi::OsStr, fs::{create_dir_all, read_to_string, write, File}, path::{Path, PathBuf}
+    env,
+    ffi::OsStr,
+    fs::{File, OpenOptions, create_dir_all, read_to_string, write},
+    io::{self, Seek, SeekFrom},
+    path::{Path, PathBuf},
 };
-use tar::Builder;
 
-pub fn get_config_path() -> PathBuf {
-    let mut path = config_dir().expect("Could not find config directory");
+// Fails when the platform has no config directory, e.g. without a home directory
+pub fn get_config_path() -> io::Result<PathBuf> {
+    let mut path = config_dir().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "Could not find config directory")
+    })?;
     path.push("snd");
     path.push("config.conf");
-    path
+    Ok(path)
 }
 
+// Without a config directory there is nothing to read and the defaults apply
 pub fn read_config() -> Config {
-    let path = get_config_path();
-    let mut follow_symlinks = false;
-    let mut send_method = "semi-reliable".to_string();
+    let mut config = Config::default();
+    let Ok(path) = get_config_path() else {
+        return config;
+    };
 
-    if path.exists() {
-        if let Ok(contents) = read_to_string(&path) {
-            for line in contents.lines() {
-                if let Some(value) = line.strip_prefix("send_method = ") {
-                    send_method = value.trim().to_string();
-                }
-                if let Some(value) = line.strip_prefix("follow_symlinks = ") {
-                    follow_symlinks = value.trim() == "true";
-                }
+    if path.exists()
+        && let Ok(contents) = read_to_string(&path)
+    {
+        for line in contents.lines() {
+            if let Some(value) = line.strip_prefix("send_method = ") {
+                config.send_method = value.trim().to_string();
+            }
+            if let Some(value) = line.strip_prefix("follow_symlinks = ") {
+                config.follow_symlinks = value.trim() == "true";
+            }
+            if let Some(value) = line.strip_prefix("encrypt = ") {
+                config.encrypt = value.trim() == "true";
+            }
+            if let Some(value) = line.strip_prefix("parallel_streams = ") {
+                config.parallel_streams = value.trim().parse().unwrap_or(4).clamp(1, 64);
+            }
+            if let Some(value) = line.strip_prefix("auto_accept_hosts = ") {
+                config.auto_accept_hosts = split_list(value);
+            }
+            if let Some(value) = line.strip_prefix("max_file_size = ") {
+                config.max_file_size = value.trim().parse().unwrap_or(0);
+            }
+            if let Some(value) = line.strip_prefix("allowed_file_types = ") {
+                config.allowed_file_types = split_list(value);
+            }
+            if let Some(value) = line.strip_prefix("peer_quota = ") {
+                config.peer_quota = value.trim().parse().unwrap_or(0);
+            }
+            if let Some(value) = line.strip_prefix("max_retries = ") {
+                config.max_retries = value.trim().parse().unwrap_or(10).max(1);
+            }
+            if let Some(value) = line.strip_prefix("idle_timeout = ") {
+                config.idle_timeout = value.trim().parse().unwrap_or(30).max(1);
+            }
+            if let Some(value) = line.strip_prefix("download_dir = ") {
+                config.download_dir = value.trim().to_string();
+            }
+            if let Some(value) = line.strip_prefix("sender_dirs = ") {
+                config.sender_dirs = split_pairs(value);
+            }
+            if let Some(value) = line.strip_prefix("on_collision = ") {
+                config.on_collision = value.trim().to_string();
             }
         }
     }
-    Config {
-        send_method,
-        follow_symlinks,
-    }
+    config
 }
 
 pub fn write_config(config: &Config) -> std::io::Result<()> {
-    let path = get_config_path();
+    let path = get_config_path()?;
     if let Some(parent) = path.parent() {
         create_dir_all(parent)?;
     }
     write(
         path,
         format!(
-            "send_method = {}\nfollow_symlinks = {}",
-            config.send_method, config.follow_symlinks
+            "send_method = {}\nfollow_symlinks = {}\nencrypt = {}\nparallel_streams = {}\nauto_accept_hosts = {}\nmax_file_size = {}\nallowed_file_types = {}\npeer_quota = {}\nmax_retries = {}\nidle_timeout = {}\ndownload_dir = {}\nsender_dirs = {}\non_collision = {}",
+            config.send_method,
+            config.follow_symlinks,
+            config.encrypt,
+            config.parallel_streams,
+            config.auto_accept_hosts.join(", "),
+            config.max_file_size,
+            config.allowed_file_types.join(", "),
+            config.peer_quota,
+            config.max_retries,
+            config.idle_timeout,
+            config.download_dir,
+            join_pairs(&config.sender_dirs),
+            config.on_collision
         ),
     )
 }
 
+// Comma separated config values, blank entries are dropped
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+// Comma separated "key:value" config values such as sender_dirs, entries without a colon are dropped
+pub fn split_pairs(value: &str) -> Vec<(String, String)> {
+    split_list(value)
+        .iter()
+        .filter_map(|pair| pair.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .filter(|(k, v)| !k.is_empty() && !v.is_empty())
+        .collect()
+}
+
+pub fn join_pairs(pairs: &[(String, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}:{}", k, v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 pub fn human_readable_size(size: u64) -> String {
     const UNITS: [&str; 6] = ["bytes", "KB", "MB", "GB", "TB", "PB"];
     let mut size = size as f64;
@@ -91,11 +174,33 @@ pub fn expand_path(input: &str) -> PathBuf {
         PathBuf::from(input)
     };
 
-    if expanded.is_relative() {
-        env::current_dir().unwrap().join(expanded)
-    } else {
-        expanded
+    // Left relative if the current directory is gone, opening it will say so
+    match env::current_dir() {
+        Ok(dir) if expanded.is_relative() => dir.join(expanded),
+        _ => expanded,
+    }
+}
+
+// Expands each input like expand_path, treating inputs that don't exist as shell style globs
+pub fn expand_paths(inputs: &[&str]) -> Result<Vec<PathBuf>, String> {
+    let mut paths = Vec::new();
+    for input in inputs {
+        let path = expand_path(input);
+        if path.exists() {
+            paths.push(path);
+            continue;
+        }
+        let pattern = path.to_string_lossy().to_string();
+        let matches: Vec<PathBuf> = glob(&pattern)
+            .map_err(|e| format!("Invalid pattern {}: {}", input, e))?
+            .filter_map(Result::ok)
+            .collect();
+        if matches.is_empty() {
+            return Err(format!("Nothing matches {}", input));
+        }
+        paths.extend(matches);
     }
+    Ok(paths)
 }
 
 pub fn get_file_type(path: &Path) -> &'static str {
@@ -198,41 +303,67 @@ pub fn gen_cname() -> String {
         .to_string()
 }
 
-pub fn extract_hostname(message: &str) -> String {
-    if let Some(start) = message.find("from ") {
-        if let Some(end) = message.find('!') {
-            if start + 5 < end {
-                return message[start + 5..end].to_string();
-            }
-        }
-    }
-    message.trim_end_matches('!').to_string()
+// Hex encoded SHA-256 of a file, sent along with offers so the receiver can check what it got
+pub fn file_digest(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
 }
 
-pub fn downloadfc(full_path: &Path) -> (File, PathBuf) {
-    let fname: &OsStr = full_path.file_name().unwrap_or_default();
-    let dld = download_dir().unwrap_or_default();
-    let nname = dld.join(fname);
-    let fp: File = File::create(&nname).expect("Failed to create file");
-    (fp, nname)
+// The folder a sender's files are saved to when the receiver doesn't pick one: the sender's entry
+// in sender_dirs (by identity fingerprint or hostname), else download_dir, else the system
+// downloads folder. Folders from the config are created if they don't exist yet
+pub fn default_download_dir(dm: &DM, config: &Config) -> Result<PathBuf, SndError> {
+    let fingerprint = dm.identity.as_deref().map(fingerprint);
+    let configured = config
+        .sender_dirs
+        .iter()
+        .find(|(sender, _)| fingerprint.as_deref() == Some(sender.as_str()))
+        .or_else(|| {
+            config
+                .sender_dirs
+                .iter()
+                .find(|(sender, _)| sender.eq_ignore_ascii_case(&dm.host_info.name))
+        })
+        .map(|(_, dir)| dir.as_str())
+        .or((!config.download_dir.is_empty()).then_some(config.download_dir.as_str()));
+    match configured {
+        Some(dir) => {
+            let dir = expand_path(dir);
+            create_dir_all(&dir)
+                .map_err(SndError::io(format!("Failed to create {}", dir.display())))?;
+            Ok(dir)
+        }
+        None => download_dir()
+            .filter(|d| d.is_dir())
+            .ok_or(SndError::NoDownloadDir),
+    }
 }
 
-// This function creates a tar file but does not remove it. Removing it should be handled by any
-// code that calls this
-pub fn tarify(fpath: String) -> PathBuf {
-    let dir_name = Path::new(&fpath)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("temp_dir");
-    
-    let tarfpth = temp_dir().join(format!("{}.tar.gz", dir_name));
-    let tarfp = File::create(&tarfpth).expect("Failed to create temp file");
-    let enc = GzEncoder::new(tarfp, Compression::default());
-    let mut tar = Builder::new(enc);
-    tar.append_dir_all("", &fpath)
-        .expect("Failed to add directory to archive");
-    tar.finish().expect("Failed to finish writing to the archive");
-    tarfpth
+// Opens the .snd-partial file a download to `saved_path` is written into, keeping the first
+// `offset` bytes when resuming
+pub fn downloadfc(saved_path: &Path, offset: u64) -> Result<File, SndError> {
+    let partial = partial_path(saved_path);
+    let mut fp: File = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(offset == 0)
+        .open(&partial)
+        .map_err(SndError::io(format!(
+            "Failed to create {}",
+            partial.display()
+        )))?;
+    if offset > 0 {
+        fp.set_len(offset)
+            .and_then(|_| fp.seek(SeekFrom::Start(offset)))
+            .map_err(SndError::io("Failed to truncate partial file"))?;
+    }
+    Ok(fp)
 }
 
 // Stolen from rust path source code since its a nightly only feature and im not bothered.
@@ -259,6 +390,22 @@ fn split_file_at_dot(file: &OsStr) -> (&OsStr, Option<&OsStr>) {
     }
 }
 
+// The path with " (n)" added to the file name before its extensions, "notes (2).tar.gz"
+pub fn numbered_path(path: &Path, n: u32) -> PathBuf {
+    let name = path.file_name().unwrap_or_default();
+    let (stem, extensions) = split_file_at_dot(name);
+    let mut numbered = stem.to_os_string();
+    numbered.push(format!(" ({})", n));
+    if let Some(extensions) = extensions {
+        numbered.push(".");
+        numbered.push(extensions);
+    }
+    path.with_file_name(numbered)
+}
+
 pub fn fpre(fpath: &Path) -> Option<&OsStr> {
-    fpath.file_name().map(split_file_at_dot).and_then(|(before, _after)| Some(before))
+    fpath
+        .file_name()
+        .map(split_file_at_dot)
+        .map(|(before, _after)| before)
 }